@@ -0,0 +1,615 @@
+// Window-free game simulation.
+// Everything that happens in a frame of gameplay lives here. The frontend only
+// has to feed in the player's input once per frame and display the PPU.
+
+use cgmath::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use nesppu;
+use nesppu::{Overscan, Ppu, RasterEffect};
+use gfx::*;
+use tilesheet;
+use tilesheet::TilesheetError;
+
+pub type Vec2f = Vector2<f32>;
+pub type Vec2i = Vector2<i32>;
+
+enum CloudDirection {
+    Left,
+    Right,
+}
+
+enum PrecipitationType {
+    Rain,
+    RainSplash,
+    Lightning,
+}
+
+struct Precipitation {
+    type_: PrecipitationType,
+    pos: Vec2f,
+    timer: u32,
+}
+
+struct Cloud {
+    direction: CloudDirection,
+    pos: Vec2f,
+    speed: f32,
+    lightning_n: u32, // Cloud has a 1/N chance of spawning a lightning bolt.
+    precipitation_period: u32, // Cloud emits precipitation every X frames.
+    timer: u32,
+}
+
+#[derive(Default)]
+struct DeathState {
+    timer: u32,
+}
+
+pub struct Game {
+    seed: u64,
+    // All gameplay randomness must come from here, so that runs can be reproduced. This is a
+    // named algorithm rather than StdRng, which may change between rand releases.
+    rng: ChaCha8Rng,
+    ppu: Ppu,
+    player_pos: Vec2f,
+    clouds: Vec<Cloud>,
+    precipitation: Vec<Precipitation>,
+    caught_rain: u32,
+    lightning_color_cycle_timer: u32,
+    lightning_color_set: usize,
+    ground_frame_timer: u32,
+    ground_frame: usize,
+    death_state: Option<DeathState>,
+    custom_chr: Option<Vec<u8>>, // Replaces the built-in patterns, from a CHR file or tilesheet
+}
+
+// TODO:
+// - tuning
+// - fancier player graphics and collision detection
+const PRECIPITATION_PERIOD: u32 = 1 * 60;
+const RAINSPLASH_TIME: u32 = 60 / 2;
+const RAINFALL_SPEED: f32 = 1.0;
+const CLOUD_SPEED: f32 = 1.0;
+const CLOUD_LEFT_BOUND: f32 = 8.0;
+const CLOUD_RIGHT_BOUND: f32 = 228.0;
+const GROUND_Y_TILE: usize = nesppu::DISPLAY_HEIGHT_IN_TILES - 4;
+const GROUND_Y: f32 = (GROUND_Y_TILE * nesppu::TILE_HEIGHT_IN_PIXELS) as f32;
+const PLAYER_Y: f32 = GROUND_Y - 8.0;
+const PLAYER_SPEED: f32 = 2.0;
+const NEW_CLOUD_SCORE: u32 = 5;
+const LIGHTNING_COLOR_CYCLE_TIME: u32 = 5;
+const DEATH_TIME: u32 = 4 * 60;
+
+// Returns a bool with a 1/N chance of being true.
+fn random_bool(rng: &mut ChaCha8Rng, n: u32) -> bool {
+    rng.gen_ratio(1, n)
+}
+
+// Returns a random u32 in the open range [lo, hi).
+fn random_u32(rng: &mut ChaCha8Rng, lo: u32, hi: u32) -> u32 {
+    rng.gen_range(lo, hi)
+}
+
+// Returns a random f32 in the open range [lo, hi).
+fn random_f32(rng: &mut ChaCha8Rng, lo: f32, hi: f32) -> f32 {
+    rng.gen_range(lo, hi)
+}
+
+impl Game {
+    // Two games created with the same seed and fed the same input will play out identically.
+    pub fn new(seed: u64) -> Self {
+        let mut this = Game {
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            ppu: Default::default(),
+            player_pos: Vec2f::new(nesppu::DISPLAY_WIDTH as f32 / 2.0, PLAYER_Y),
+            clouds: Vec::new(),
+            precipitation: Vec::new(),
+            caught_rain: 0,
+            lightning_color_cycle_timer: 0,
+            lightning_color_set: 0,
+            ground_frame_timer: 0,
+            ground_frame: 0,
+            death_state: None,
+            custom_chr: None,
+        };
+
+        this.reset();
+
+        this
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    // For display settings only. Anything that affects gameplay belongs in Game.
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    pub fn caught_rain(&self) -> u32 {
+        self.caught_rain
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.death_state.is_some()
+    }
+
+    // Use the patterns in a CHR file instead of the built-in ones, from now on
+    pub fn load_chr<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let chr = fs::read(path)?;
+        self.ppu.load_chr(&chr)?;
+        self.custom_chr = Some(chr);
+        self.load_patterns();
+        Ok(())
+    }
+
+    // Use the tiles in a PNG tilesheet instead of the built-in patterns, from now on. The tiles
+    // replace patterns from 0 up: a sheet 16 tiles wide has the background patterns in its first
+    // 16 rows and the sprite patterns in the next 16.
+    pub fn load_tilesheet<P: AsRef<Path>>(&mut self, path: P) -> Result<(), TilesheetError> {
+        tilesheet::load_tilesheet(&mut self.ppu, path, 0)?;
+        self.custom_chr = Some(self.ppu.chr());
+        self.load_patterns();
+        Ok(())
+    }
+
+    fn load_patterns(&mut self) {
+        load_all_patterns(&mut self.ppu);
+        if let Some(ref chr) = self.custom_chr {
+            // Custom patterns have no animation frames, so the ground stays still
+            self.ppu.set_num_chr_banks(nesppu::NUM_CHR_WINDOWS);
+            self.ppu.load_chr(chr).unwrap();
+        }
+    }
+
+    // The ground and at least one row of tiles below it for the scorebar must be visible.
+    pub fn set_overscan(&mut self, overscan: Overscan) -> Result<(), String> {
+        let area = overscan.visible_area();
+        // Checked here rather than left to the PPU, which panics on an area outside the display
+        let inside_display = area.x < nesppu::DISPLAY_WIDTH && area.y < nesppu::DISPLAY_HEIGHT &&
+            area.width > 0 && area.width <= nesppu::DISPLAY_WIDTH - area.x &&
+            area.height > 0 && area.height <= nesppu::DISPLAY_HEIGHT - area.y;
+        if !inside_display {
+            return Err(format!("the visible area must be inside the {}x{} display",
+                               nesppu::DISPLAY_WIDTH, nesppu::DISPLAY_HEIGHT));
+        }
+        let ground_top = GROUND_Y_TILE * nesppu::TILE_HEIGHT_IN_PIXELS;
+        let ground_visible = area.y <= ground_top;
+        let scorebar_visible = (area.y + area.height) / nesppu::TILE_HEIGHT_IN_PIXELS > GROUND_Y_TILE + 1;
+        if !ground_visible || !scorebar_visible {
+            return Err(format!("the visible area must include lines {} to {}",
+                               ground_top, ground_top + 2 * nesppu::TILE_HEIGHT_IN_PIXELS - 1));
+        }
+        // The score is drawn leftward from the last whole tile on the right, and can be up to
+        // 10 digits long, so 10 whole tiles must be visible.
+        let first_whole_tile = area.x.div_ceil(nesppu::TILE_WIDTH_IN_PIXELS);
+        let end_whole_tile = (area.x + area.width) / nesppu::TILE_WIDTH_IN_PIXELS;
+        if end_whole_tile < first_whole_tile + 10 {
+            return Err(format!("the visible area must be at least 10 whole tiles ({} pixels) wide",
+                               10 * nesppu::TILE_WIDTH_IN_PIXELS));
+        }
+
+        self.ppu.set_overscan(overscan);
+
+        // Clear the scorebar from wherever it was
+        self.draw_ground();
+        self.draw_scorebar();
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        self.player_pos = Vec2f::new(nesppu::DISPLAY_WIDTH as f32 / 2.0, PLAYER_Y);
+        self.clouds = Vec::new();
+        self.precipitation = Vec::new();
+        self.caught_rain = 0;
+        self.lightning_color_cycle_timer = 0;
+        self.lightning_color_set = 0;
+        self.ground_frame_timer = 0;
+        self.ground_frame = 0;
+        self.death_state = None;
+
+        // Clear PPU. It is not replaced outright, so that the frontend's display settings stick.
+        self.ppu.clear_sprites();
+        self.ppu.clear_raster_effects();
+        self.ppu.set_emphasis(0);
+        for y in 0..nesppu::TILEMAP_HEIGHT_IN_TILES {
+            for x in 0..nesppu::TILEMAP_WIDTH_IN_TILES {
+                self.ppu.set_tile(x, y, BLANK_PATTERN_NAME);
+                self.ppu.set_attribute(x, y, 0);
+            }
+        }
+
+        self.load_patterns();
+
+        // Set background color to black
+        self.ppu.set_common_bg_color(BACKGROUND_COLOR);
+
+        // Set background palettes
+        self.ppu.set_bg_colors(NORMAL_GROUND_ATTRIB, NORMAL_GROUND_COLORS);
+        self.ppu.set_bg_colors(GRAYS_ATTRIB, GRAYS_COLORS);
+
+        // Set sprite palettes
+        self.ppu.set_sprite_colors(PLAYER_ATTRIB, PLAYER_COLORS);
+        self.ppu.set_sprite_colors(CLOUD_ATTRIB, CLOUD_COLORS);
+        self.ppu.set_sprite_colors(LIGHTNING_ATTRIB, LIGHTNING_COLOR_SETS[self.lightning_color_set]);
+        self.ppu.set_sprite_colors(RAIN_ATTRIB, RAIN_COLORS);
+
+        self.draw_ground();
+
+        // Spawn the first cloud
+        self.spawn_cloud(Vec2f::new(50.0, 20.0));
+    }
+
+    fn spawn_cloud(&mut self, pos: Vec2f) {
+        self.clouds.push(Cloud {
+            direction: if random_bool(&mut self.rng, 2) { CloudDirection::Left } else { CloudDirection::Right },
+            pos: pos,
+            speed: CLOUD_SPEED,
+            lightning_n: 4, // TODO: configurable lightning frequency
+            precipitation_period: PRECIPITATION_PERIOD, // TODO: configurable precipitation period
+            timer: 0,
+        });
+    }
+
+    fn spawn_lightning(&mut self, pos: Vec2f) {
+        self.precipitation.push(Precipitation {
+            type_: PrecipitationType::Lightning,
+            pos: pos,
+            timer: 0,
+        });
+    }
+
+    fn spawn_raindrop(&mut self, pos: Vec2f) {
+        self.precipitation.push(Precipitation {
+            type_: PrecipitationType::Rain,
+            pos: pos,
+            timer: 0,
+        });
+    }
+
+    // This function exists because self.precipitation.drain_filter cannot be run
+    // in advance_frame_playing. The only way to coax the borrow checker to accept
+    // this code is to split out the fields from Game and pass them to this function.
+    //
+    // Thanks a lot, Rust.
+    fn run_precipitation(
+        precipitation: &mut Vec<Precipitation>,
+        caught_rain: &mut u32,
+        death_state: &mut Option<DeathState>,
+        player_pos: &mut Vec2f,
+        sprite_index: &mut usize,
+        ppu: &mut Ppu
+    ) -> u32 {
+        // Simulate precipitation
+        let mut num_new_clouds = 0;
+        precipitation.drain_filter(|p| {
+            if let PrecipitationType::RainSplash = p.type_ {
+                p.timer += 1;
+                if p.timer >= RAINSPLASH_TIME {
+                    return true;
+                }
+            } else {
+                p.pos.y += RAINFALL_SPEED;
+                if p.pos.y > GROUND_Y - 8.0 {
+                    p.pos.y = GROUND_Y - 8.0;
+                    if let PrecipitationType::Rain = p.type_ {
+                        p.type_ = PrecipitationType::RainSplash;
+                        p.timer = 0;
+                    } else {
+                        return true;
+                    }
+                }
+            }
+
+            // Check if player caught rain/lightning
+
+            // Compute player hitbox
+            let (pl, pt, pr, pb) = (player_pos.x, player_pos.y,
+                                    player_pos.x + 8.0, player_pos.y + 8.0);
+
+            // Compute rain hitbox (or hitPOINT, really.)
+            let (rx, ry) = (p.pos.x + 4.0, p.pos.y + 8.0);
+
+            // Check for hit
+            if rx >= pl && rx <= pr && ry >= pt && ry <= pb {
+                if let PrecipitationType::Rain = p.type_ {
+                    *caught_rain += 1;
+                    if *caught_rain % NEW_CLOUD_SCORE == 0 {
+                        num_new_clouds += 1;
+                    }
+                    return true;
+                } else if let PrecipitationType::Lightning = p.type_ {
+                    // NOTE: this isn't elegant, but setting DeathState will trigger the death
+                    //       sequence on the next frame.
+                    *death_state = Some(DeathState::default());
+                }
+            }
+
+            let (pattern_name, attrib) = match p.type_ {
+                PrecipitationType::Rain => (RAIN_PATTERN_NAME, RAIN_ATTRIB),
+                PrecipitationType::RainSplash => (RAINSPLASH_PATTERN_NAME, RAIN_ATTRIB),
+                PrecipitationType::Lightning => (LIGHTNING_PATTERN_NAME, LIGHTNING_ATTRIB),
+            };
+            ppu.set_sprite(*sprite_index, p.pos.x as i32, p.pos.y as i32, pattern_name, attrib);
+            *sprite_index += 1;
+
+            return false;
+        });
+
+        num_new_clouds
+    }
+
+    fn advance_frame_playing(&mut self, direction: Vec2f) {
+        // Move the player
+        self.player_pos.x += direction.x * PLAYER_SPEED;
+
+        // Drive lightning color cycles
+        self.lightning_color_cycle_timer += 1;
+        if self.lightning_color_cycle_timer >= LIGHTNING_COLOR_CYCLE_TIME {
+            self.lightning_color_cycle_timer = 0;
+            self.lightning_color_set += 1;
+            if self.lightning_color_set >= NUM_LIGHTNING_COLOR_SETS {
+                self.lightning_color_set = 0;
+            }
+
+            self.ppu.set_sprite_colors(LIGHTNING_ATTRIB,
+                                       LIGHTNING_COLOR_SETS[self.lightning_color_set]);
+        }
+
+        self.ppu.clear_sprites();
+
+        let mut sprite_index = 0;
+
+        self.draw_player(&mut sprite_index);
+
+        // Simulate clouds
+        for cn in 0..self.clouds.len() {
+            let mut cloud = &mut self.clouds[cn];
+
+            cloud.pos.x += match cloud.direction {
+                CloudDirection::Left => -cloud.speed,
+                CloudDirection::Right => cloud.speed,
+            };
+
+            if cloud.pos.x < CLOUD_LEFT_BOUND {
+                cloud.pos.x = CLOUD_LEFT_BOUND;
+                cloud.direction = CloudDirection::Right;
+            } else if cloud.pos.x > CLOUD_RIGHT_BOUND {
+                cloud.pos.x = CLOUD_RIGHT_BOUND;
+                cloud.direction = CloudDirection::Left;
+            }
+
+            self.ppu.set_sprite(sprite_index, cloud.pos.x as i32, cloud.pos.y as i32,
+                                CLOUD_LEFT_PATTERN_NAME, CLOUD_ATTRIB);
+            sprite_index += 1;
+            // The right half is the left half mirrored
+            self.ppu.set_sprite(sprite_index, cloud.pos.x as i32 + 8, cloud.pos.y as i32,
+                                CLOUD_LEFT_PATTERN_NAME, CLOUD_ATTRIB | nesppu::SPRITE_FLIP_HORIZ);
+            sprite_index += 1;
+
+            // Spawn new raindrops and/or lightning bolts
+            cloud.timer += 1;
+            if cloud.timer >= cloud.precipitation_period {
+                cloud.timer = 0;
+                if random_bool(&mut self.rng, cloud.lightning_n) {
+                    let pos = cloud.pos;
+                    self.spawn_lightning(pos);
+                } else {
+                    let pos = cloud.pos;
+                    self.spawn_raindrop(pos);
+                }
+            }
+        }
+
+        let num_new_clouds = Game::run_precipitation(
+            &mut self.precipitation,
+            &mut self.caught_rain,
+            &mut self.death_state,
+            &mut self.player_pos,
+            &mut sprite_index,
+            &mut self.ppu
+        );
+
+        // Spawn new clouds if score increased enough
+        for _ in 0..num_new_clouds {
+            let x = random_f32(&mut self.rng, CLOUD_LEFT_BOUND, CLOUD_RIGHT_BOUND);
+            let y = random_f32(&mut self.rng, 10.0, 60.0);
+            self.spawn_cloud(Vec2f::new(x, y));
+        }
+
+        self.draw_scorebar();
+    }
+
+    fn advance_frame_death(&mut self, direction: Vec2f) {
+        self.ppu.clear_sprites();
+
+        let mut sprite_index = 0;
+
+        self.draw_player(&mut sprite_index);
+
+        self.draw_scorebar();
+
+        let death_state = self.death_state.as_mut().unwrap();
+
+        self.ppu.clear_raster_effects();
+        if death_state.timer % 30 < 15 && death_state.timer < 2 * 60 {
+            // Flash the sky, but not the ground and scorebar. The tint covers everything.
+            self.ppu.set_common_bg_color(FLASH_COLOR);
+            self.ppu.add_raster_effect(GROUND_Y_TILE * nesppu::TILE_HEIGHT_IN_PIXELS,
+                                       RasterEffect::PaletteColor(0, BACKGROUND_COLOR));
+            self.ppu.set_emphasis(FLASH_EMPHASIS);
+        } else {
+            self.ppu.set_common_bg_color(BACKGROUND_COLOR);
+            self.ppu.set_emphasis(0);
+        }
+
+        death_state.timer += 1;
+        if death_state.timer >= DEATH_TIME {
+            // Reset the game
+            self.reset();
+        }
+    }
+
+    // Runs one 60th of a second of gameplay. direction is the player's input for this frame.
+    pub fn advance_frame(&mut self, direction: Vec2f) {
+        let mut direction = direction;
+        if direction.magnitude2() > 1.0 {
+            direction = direction.normalize();
+        }
+
+        // Ripple the ground
+        if self.custom_chr.is_none() {
+            self.ground_frame_timer += 1;
+            if self.ground_frame_timer >= GROUND_FRAME_TIME {
+                self.ground_frame_timer = 0;
+                self.ground_frame = (self.ground_frame + 1) % NUM_GROUND_FRAMES;
+                show_ground_frame(&mut self.ppu, self.ground_frame);
+            }
+        }
+
+        if self.death_state.is_some() {
+            self.advance_frame_death(direction);
+        } else {
+            self.advance_frame_playing(direction);
+        }
+
+        self.ppu.end_frame();
+    }
+
+    fn draw_player(&mut self, sprite_index: &mut usize) {
+        // Draw player
+        self.ppu.set_sprite(*sprite_index, self.player_pos.x as i32, self.player_pos.y as i32,
+                            PLAYER_PATTERN_NAME, PLAYER_ATTRIB);
+        *sprite_index += 1;
+        self.ppu.set_sprite(*sprite_index, self.player_pos.x as i32, self.player_pos.y as i32 - 8,
+                            PLAYER_TOP_PATTERN_NAME, PLAYER_ATTRIB);
+        *sprite_index += 1;
+    }
+
+    fn draw_ground(&mut self) {
+        // Draw ground
+        for y in GROUND_Y_TILE+1..nesppu::DISPLAY_HEIGHT_IN_TILES {
+            for x in 0..nesppu::DISPLAY_WIDTH_IN_TILES {
+                self.ppu.set_tile(x, y, GROUND_PATTERN_NAME);
+                self.ppu.set_attribute(x, y, NORMAL_GROUND_ATTRIB);
+            }
+        }
+
+        // Draw top of ground
+        for x in 0..nesppu::DISPLAY_WIDTH_IN_TILES {
+            self.ppu.set_tile(x, GROUND_Y_TILE, GROUND_TOP_PATTERN_NAME);
+            self.ppu.set_attribute(x, GROUND_Y_TILE, NORMAL_GROUND_ATTRIB);
+        }
+    }
+
+    fn draw_scorebar(&mut self) {
+        // The scorebar goes on the bottom row of tiles that is entirely visible.
+        let area = self.ppu.visible_area();
+        let scorebar_y = (area.y + area.height) / nesppu::TILE_HEIGHT_IN_PIXELS - 1;
+
+        for x in 0..nesppu::DISPLAY_WIDTH_IN_TILES {
+            self.ppu.set_tile(x, scorebar_y, BLANK_PATTERN_NAME);
+            self.ppu.set_attribute(x, scorebar_y, GRAYS_ATTRIB);
+        }
+
+        let mut score = self.caught_rain;
+        let mut score_x = (area.x + area.width) / nesppu::TILE_WIDTH_IN_PIXELS - 1;
+        let mut draw_score = true;
+        while draw_score {
+            let digit = score % 10;
+            self.ppu.set_tile(score_x, scorebar_y, NUM0_PATTERN_NAME + digit as u8);
+            score /= 10;
+            score_x -= 1;
+            draw_score = score != 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_FRAMES: usize = 10_000;
+
+    // Wanders left and right, holding each direction for a while.
+    fn test_input(frame: usize) -> Vec2f {
+        match frame / 45 * 7 % 5 {
+            0 => Vec2f::new(-1.0, 0.0),
+            1 => Vec2f::new(1.0, 0.0),
+            2 => Vec2f::new(0.0, 0.0),
+            3 => Vec2f::new(-1.0, -1.0),
+            _ => Vec2f::new(1.0, 1.0),
+        }
+    }
+
+    // Everything that should be identical between two runs with the same seed and input.
+    fn snapshot(game: &Game) -> (Vec2f, u32, usize, usize, bool) {
+        (game.player_pos, game.caught_rain, game.clouds.len(), game.precipitation.len(),
+         game.is_dead())
+    }
+
+    #[test]
+    fn same_seed_and_input_are_deterministic() {
+        let mut first = Game::new(42);
+        let mut second = Game::new(42);
+        let mut death_frame = None;
+        for frame in 0..MAX_FRAMES {
+            first.advance_frame(test_input(frame));
+            second.advance_frame(test_input(frame));
+            assert_eq!(snapshot(&first), snapshot(&second), "runs diverged on frame {}", frame);
+            if first.is_dead() {
+                death_frame = Some(frame);
+                break;
+            }
+        }
+        assert!(death_frame.is_some(), "the player survived {} frames", MAX_FRAMES);
+    }
+
+    #[test]
+    fn game_resets_after_death() {
+        let mut game = Game::new(42);
+        let mut frame = 0;
+        while !game.is_dead() {
+            assert!(frame < MAX_FRAMES, "the player survived {} frames", MAX_FRAMES);
+            game.advance_frame(test_input(frame));
+            frame += 1;
+        }
+        for _ in 0..DEATH_TIME {
+            game.advance_frame(Vec2f::new(0.0, 0.0));
+        }
+        assert!(!game.is_dead());
+        assert_eq!(game.caught_rain(), 0);
+        assert_eq!(game.seed(), 42);
+    }
+
+    #[test]
+    fn overscan_must_fit_the_score() {
+        let mut game = Game::new(42);
+        let custom = |left, visible_width| {
+            Overscan::Custom(nesppu::DisplayRect { x: left, y: 0, width: visible_width, height: 240 })
+        };
+        assert!(game.set_overscan(Overscan::Ntsc).is_ok());
+        assert!(game.set_overscan(custom(0, 80)).is_ok());
+        assert!(game.set_overscan(custom(0, 79)).is_err());
+        // Tiles cut off on the left don't count
+        assert!(game.set_overscan(custom(4, 84)).is_ok());
+        assert!(game.set_overscan(custom(4, 83)).is_err());
+        assert!(game.set_overscan(custom(4, 4)).is_err());
+
+        // Areas outside the display are an error, not a panic
+        assert!(game.set_overscan(custom(100, 200)).is_err());
+        assert!(game.set_overscan(custom(usize::MAX, 80)).is_err());
+        assert!(game.set_overscan(custom(0, usize::MAX)).is_err());
+        assert!(game.set_overscan(Overscan::Custom(nesppu::DisplayRect { x: 0, y: 8, width: 256, height: 240 })).is_err());
+    }
+}
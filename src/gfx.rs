@@ -10,19 +10,67 @@ pub const GRAYS_COLORS: [u8; 3] = [0x00, 0x10, 0x20];
 
 pub const BACKGROUND_COLOR: u8 = 0x1D; // Black
 
+// The lightning strike flash: a white sky, with everything tinted blue
+pub const FLASH_COLOR: u8 = 0x20;
+pub const FLASH_EMPHASIS: u8 = nesppu::MASK_EMPHASIZE_BLUE;
+
+// The background uses pattern table 0 and sprites use pattern table 1, so each has 256 names.
+pub const BG_PATTERN_TABLE: usize = 0;
+pub const SPRITE_PATTERN_TABLE: usize = 1;
+
 pub const BLANK_PATTERN_NAME: u8 = 0;
 pub const BLANK_PATTERN: [u8; nesppu::PATTERN_SIZE_IN_BYTES] = [0u8; nesppu::PATTERN_SIZE_IN_BYTES];
 
-pub const GROUND_PATTERN_NAME: u8 = 1;
-pub const GROUND_PATTERN_CHARS: CharPattern = [
-    *b"....;...",
-    *b";.....;.",
-    *b"..;.....",
-    *b".;...;..",
-    *b".;......",
-    *b"......;.",
-    *b"..;...;.",
-    *b";...;...",
+// The ground ripples with the rain. Its animation frames are CHR banks of their own, after the
+// pattern tables, and are switched into the window of the background pattern table that the
+// ground pattern is in. Nothing else is in that window.
+pub const GROUND_PATTERN_NAME: u8 = 64;
+pub const GROUND_CHR_WINDOW: usize =
+    (BG_PATTERN_TABLE * nesppu::PATTERNS_PER_TABLE + GROUND_PATTERN_NAME as usize) / nesppu::PATTERNS_PER_CHR_BANK;
+pub const GROUND_FIRST_CHR_BANK: usize = nesppu::NUM_CHR_WINDOWS;
+pub const GROUND_FRAME_TIME: u32 = 8;
+pub const NUM_GROUND_FRAMES: usize = 4;
+pub const GROUND_FRAMES_CHARS: [CharPattern; NUM_GROUND_FRAMES] = [
+    [
+        *b"....;...",
+        *b";.....;.",
+        *b"..;.....",
+        *b".;...;..",
+        *b".;......",
+        *b"......;.",
+        *b"..;...;.",
+        *b";...;...",
+    ],
+    [
+        *b"....;...",
+        *b";.....;.",
+        *b"..;.....",
+        *b".;.;;...",
+        *b".;.;;...",
+        *b"......;.",
+        *b"..;...;.",
+        *b";...;...",
+    ],
+    [
+        *b"....;...",
+        *b";.....;.",
+        *b"..;;;;..",
+        *b".;;..;..",
+        *b".;;..;..",
+        *b"..;;;;;.",
+        *b"..;...;.",
+        *b";...;...",
+    ],
+    [
+        *b"....;...",
+        *b";;.;..;;",
+        *b".;.....;",
+        *b".;......",
+        *b";.......",
+        *b".;.....;",
+        *b"..;;.;;.",
+        *b";...;...",
+    ],
 ];
 
 pub const GROUND_TOP_PATTERN_NAME: u8 = 2;
@@ -148,7 +196,8 @@ pub const NUM9_PATTERN_CHARS: CharPattern = [
     *b"        ",
 ];
 
-pub const RAINSPLASH_PATTERN_NAME: u8 = 249;
+// Sprite patterns, in the sprite pattern table
+pub const RAINSPLASH_PATTERN_NAME: u8 = 1;
 pub const RAINSPLASH_PATTERN_CHARS: CharPattern = [
     *b"        ",
     *b"        ",
@@ -160,7 +209,7 @@ pub const RAINSPLASH_PATTERN_CHARS: CharPattern = [
     *b"   ...  ",
 ];
 
-pub const RAIN_PATTERN_NAME: u8 = 250;
+pub const RAIN_PATTERN_NAME: u8 = 2;
 pub const RAIN_PATTERN_CHARS: CharPattern = [
     *b"    .   ",
     *b"    .   ",
@@ -172,7 +221,7 @@ pub const RAIN_PATTERN_CHARS: CharPattern = [
     *b"    .   ",
 ];
 
-pub const LIGHTNING_PATTERN_NAME: u8 = 251;
+pub const LIGHTNING_PATTERN_NAME: u8 = 3;
 pub const LIGHTNING_PATTERN_CHARS: CharPattern = [
     *b"    .   ",
     *b"   ..   ",
@@ -184,7 +233,8 @@ pub const LIGHTNING_PATTERN_CHARS: CharPattern = [
     *b"    .   ",
 ];
 
-pub const CLOUD_LEFT_PATTERN_NAME: u8 = 252;
+// The left half of a cloud. The right half is the same pattern, flipped horizontally.
+pub const CLOUD_LEFT_PATTERN_NAME: u8 = 4;
 pub const CLOUD_LEFT_PATTERN_CHARS: CharPattern = [
     *b"      ..",
     *b"  .. ...",
@@ -196,19 +246,7 @@ pub const CLOUD_LEFT_PATTERN_CHARS: CharPattern = [
     *b"    ....",
 ];
 
-pub const CLOUD_RIGHT_PATTERN_NAME: u8 = 253;
-pub const CLOUD_RIGHT_PATTERN_CHARS: CharPattern = [
-    *b"..      ",
-    *b"... ..  ",
-    *b"....... ",
-    *b"........",
-    *b"........",
-    *b"....... ",
-    *b".....   ",
-    *b".       ",
-];
-
-pub const PLAYER_TOP_PATTERN_NAME: u8 = 254;
+pub const PLAYER_TOP_PATTERN_NAME: u8 = 6;
 pub const PLAYER_TOP_PATTERN_CHARS: CharPattern = [
     *b"        ",
     *b"        ",
@@ -220,7 +258,7 @@ pub const PLAYER_TOP_PATTERN_CHARS: CharPattern = [
     *b".      .",
 ];
 
-pub const PLAYER_PATTERN_NAME: u8 = 255;
+pub const PLAYER_PATTERN_NAME: u8 = 7;
 pub const PLAYER_PATTERN_CHARS: CharPattern = [
     *b"........",
     *b" ...... ",
@@ -263,25 +301,46 @@ pub fn decode_pattern_chars(cp: CharPattern) -> [u8; nesppu::PATTERN_SIZE_IN_BYT
     pattern
 }
 
+fn bg_pattern_index(name: u8) -> usize {
+    BG_PATTERN_TABLE * nesppu::PATTERNS_PER_TABLE + name as usize
+}
+
+fn sprite_pattern_index(name: u8) -> usize {
+    SPRITE_PATTERN_TABLE * nesppu::PATTERNS_PER_TABLE + name as usize
+}
+
+// Loads every pattern and selects the pattern tables they're in
 pub fn load_all_patterns(ppu: &mut nesppu::Ppu) {
-    ppu.set_pattern(BLANK_PATTERN_NAME as usize, BLANK_PATTERN);
-    ppu.set_pattern(GROUND_PATTERN_NAME as usize, decode_pattern_chars(GROUND_PATTERN_CHARS));
-    ppu.set_pattern(GROUND_TOP_PATTERN_NAME as usize, decode_pattern_chars(GROUND_TOP_PATTERN_CHARS));
-    ppu.set_pattern(NUM0_PATTERN_NAME as usize, decode_pattern_chars(NUM0_PATTERN_CHARS));
-    ppu.set_pattern(NUM1_PATTERN_NAME as usize, decode_pattern_chars(NUM1_PATTERN_CHARS));
-    ppu.set_pattern(NUM2_PATTERN_NAME as usize, decode_pattern_chars(NUM2_PATTERN_CHARS));
-    ppu.set_pattern(NUM3_PATTERN_NAME as usize, decode_pattern_chars(NUM3_PATTERN_CHARS));
-    ppu.set_pattern(NUM4_PATTERN_NAME as usize, decode_pattern_chars(NUM4_PATTERN_CHARS));
-    ppu.set_pattern(NUM5_PATTERN_NAME as usize, decode_pattern_chars(NUM5_PATTERN_CHARS));
-    ppu.set_pattern(NUM6_PATTERN_NAME as usize, decode_pattern_chars(NUM6_PATTERN_CHARS));
-    ppu.set_pattern(NUM7_PATTERN_NAME as usize, decode_pattern_chars(NUM7_PATTERN_CHARS));
-    ppu.set_pattern(NUM8_PATTERN_NAME as usize, decode_pattern_chars(NUM8_PATTERN_CHARS));
-    ppu.set_pattern(NUM9_PATTERN_NAME as usize, decode_pattern_chars(NUM9_PATTERN_CHARS));
-    ppu.set_pattern(RAINSPLASH_PATTERN_NAME as usize, decode_pattern_chars(RAINSPLASH_PATTERN_CHARS));
-    ppu.set_pattern(RAIN_PATTERN_NAME as usize, decode_pattern_chars(RAIN_PATTERN_CHARS));
-    ppu.set_pattern(LIGHTNING_PATTERN_NAME as usize, decode_pattern_chars(LIGHTNING_PATTERN_CHARS));
-    ppu.set_pattern(CLOUD_LEFT_PATTERN_NAME as usize, decode_pattern_chars(CLOUD_LEFT_PATTERN_CHARS));
-    ppu.set_pattern(CLOUD_RIGHT_PATTERN_NAME as usize, decode_pattern_chars(CLOUD_RIGHT_PATTERN_CHARS));
-    ppu.set_pattern(PLAYER_TOP_PATTERN_NAME as usize, decode_pattern_chars(PLAYER_TOP_PATTERN_CHARS));
-    ppu.set_pattern(PLAYER_PATTERN_NAME as usize, decode_pattern_chars(PLAYER_PATTERN_CHARS));
+    ppu.set_num_chr_banks(GROUND_FIRST_CHR_BANK + NUM_GROUND_FRAMES);
+    ppu.set_bg_pattern_table(BG_PATTERN_TABLE);
+    ppu.set_sprite_pattern_table(SPRITE_PATTERN_TABLE);
+
+    for (frame, &chars) in GROUND_FRAMES_CHARS.iter().enumerate() {
+        show_ground_frame(ppu, frame);
+        ppu.set_pattern(bg_pattern_index(GROUND_PATTERN_NAME), decode_pattern_chars(chars));
+    }
+    show_ground_frame(ppu, 0);
+
+    ppu.set_pattern(bg_pattern_index(BLANK_PATTERN_NAME), BLANK_PATTERN);
+    ppu.set_pattern(bg_pattern_index(GROUND_TOP_PATTERN_NAME), decode_pattern_chars(GROUND_TOP_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM0_PATTERN_NAME), decode_pattern_chars(NUM0_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM1_PATTERN_NAME), decode_pattern_chars(NUM1_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM2_PATTERN_NAME), decode_pattern_chars(NUM2_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM3_PATTERN_NAME), decode_pattern_chars(NUM3_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM4_PATTERN_NAME), decode_pattern_chars(NUM4_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM5_PATTERN_NAME), decode_pattern_chars(NUM5_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM6_PATTERN_NAME), decode_pattern_chars(NUM6_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM7_PATTERN_NAME), decode_pattern_chars(NUM7_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM8_PATTERN_NAME), decode_pattern_chars(NUM8_PATTERN_CHARS));
+    ppu.set_pattern(bg_pattern_index(NUM9_PATTERN_NAME), decode_pattern_chars(NUM9_PATTERN_CHARS));
+    ppu.set_pattern(sprite_pattern_index(RAINSPLASH_PATTERN_NAME), decode_pattern_chars(RAINSPLASH_PATTERN_CHARS));
+    ppu.set_pattern(sprite_pattern_index(RAIN_PATTERN_NAME), decode_pattern_chars(RAIN_PATTERN_CHARS));
+    ppu.set_pattern(sprite_pattern_index(LIGHTNING_PATTERN_NAME), decode_pattern_chars(LIGHTNING_PATTERN_CHARS));
+    ppu.set_pattern(sprite_pattern_index(CLOUD_LEFT_PATTERN_NAME), decode_pattern_chars(CLOUD_LEFT_PATTERN_CHARS));
+    ppu.set_pattern(sprite_pattern_index(PLAYER_TOP_PATTERN_NAME), decode_pattern_chars(PLAYER_TOP_PATTERN_CHARS));
+    ppu.set_pattern(sprite_pattern_index(PLAYER_PATTERN_NAME), decode_pattern_chars(PLAYER_PATTERN_CHARS));
+}
+
+pub fn show_ground_frame(ppu: &mut nesppu::Ppu, frame: usize) {
+    ppu.set_chr_bank(nesppu::ChrBankSize::Size1K, GROUND_CHR_WINDOW, GROUND_FIRST_CHR_BANK + frame);
 }
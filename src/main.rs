@@ -1,4 +1,5 @@
 #![feature(drain_filter)]
+#![cfg_attr(test, feature(test))]
 
 extern crate piston;
 extern crate piston_window;
@@ -6,8 +7,12 @@ extern crate graphics;
 extern crate glutin_window;
 extern crate opengl_graphics;
 extern crate image;
+extern crate png;
 extern crate cgmath;
 extern crate rand;
+extern crate rand_chacha;
+#[cfg(test)]
+extern crate test;
 
 use piston::window::WindowSettings;
 use piston::event_loop::*;
@@ -20,444 +25,393 @@ use opengl_graphics as ogl;
 use image::{ImageBuffer, Rgba, RgbaImage};
 use cgmath::*;
 use rand::Rng;
-
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::process;
+
+// nesppu is a general-purpose PPU with more API than this game uses: the NES-style registers,
+// mirroring, CHR banking, raster effects and several output formats. Its tests cover all of it,
+// so unused parts of it are not dead code.
+#[allow(dead_code)]
 mod nesppu;
 mod gfx;
+mod game;
+mod replay;
+mod tilesheet;
 
-use nesppu::Ppu;
-use gfx::*;
-
-type Vec2f = Vector2<f32>;
-type Vec2i = Vector2<i32>;
-
-enum CloudDirection {
-    Left,
-    Right,
-}
-
-enum PrecipitationType {
-    Rain,
-    RainSplash,
-    Lightning,
-}
-
-struct Precipitation {
-    type_: PrecipitationType,
-    pos: Vec2f,
-    timer: u32,
-}
-
-struct Cloud {
-    direction: CloudDirection,
-    pos: Vec2f,
-    speed: f32,
-    lightning_n: u32, // Cloud has a 1/N chance of spawning a lightning bolt.
-    precipitation_period: u32, // Cloud emits precipitation every X frames.
-    timer: u32,
-}
-
-#[derive(Default)]
-struct DeathState {
-    timer: u32,
-}
+use nesppu::{DisplayRect, Overscan, SpriteLimits, SystemPalette};
+use nesppu::{MASK_SHOW_BG, MASK_SHOW_BG_LEFT, MASK_SHOW_SPRITES, MASK_SHOW_SPRITES_LEFT};
+use game::{Game, Vec2f};
+use replay::Replay;
 
 struct App {
     gl: ogl::GlGraphics,
-    ppu: Ppu,
+    game: Game,
     ppu_image: RgbaImage,
     ppu_texture: ogl::Texture,
-    player_pos: Vec2f,
-    clouds: Vec<Cloud>,
-    precipitation: Vec<Precipitation>,
-    caught_rain: u32,
-    lightning_color_cycle_timer: u32,
-    lightning_color_set: usize,
-    death_state: Option<DeathState>,
-}
-
-// TODO:
-// - max sprite limit
-// - tuning
-// - fancier player graphics and collision detection
-const PRECIPITATION_PERIOD: u32 = 1 * 60;
-const RAINSPLASH_TIME: u32 = 60 / 2;
-const RAINFALL_SPEED: f32 = 1.0;
-const CLOUD_SPEED: f32 = 1.0;
-const CLOUD_LEFT_BOUND: f32 = 8.0;
-const CLOUD_RIGHT_BOUND: f32 = 228.0;
-const GROUND_Y_TILE: usize = nesppu::DISPLAY_HEIGHT_IN_TILES - 4;
-const GROUND_Y: f32 = (GROUND_Y_TILE * nesppu::TILE_HEIGHT_IN_PIXELS) as f32;
-const PLAYER_Y: f32 = GROUND_Y - 8.0;
-const PLAYER_SPEED: f32 = 2.0;
-const NEW_CLOUD_SCORE: u32 = 5;
-const LIGHTNING_COLOR_CYCLE_TIME: u32 = 5;
-const DEATH_TIME: u32 = 4 * 60;
-
-// Returns a bool with a 1/N chance of being true.
-fn random_bool(n: u32) -> bool {
-    rand::thread_rng().gen_ratio(1, n)
-}
-
-// Returns a random u32 in the open range [lo, hi).
-fn random_u32(lo: u32, hi: u32) -> u32 {
-    rand::thread_rng().gen_range(lo, hi)
-}
-
-// Returns a random f32 in the open range [lo, hi).
-fn random_f32(lo: f32, hi: f32) -> f32 {
-    rand::thread_rng().gen_range(lo, hi)
+    sprite_overflow: bool,
+    palettes: Vec<(String, SystemPalette)>,
+    palette_index: usize,
+    // Shown instead of the game while show_debug_view is on
+    debug_image: RgbaImage,
+    debug_texture: ogl::Texture,
+    show_debug_view: bool,
+    debug_view_palette: u8,
 }
 
 impl App {
-    fn new(opengl: glutin_window::OpenGL) -> Self {
+    fn new(opengl: glutin_window::OpenGL, game: Game, palettes: Vec<(String, SystemPalette)>) -> Self {
         let mut ppu_texture_settings = ogl::TextureSettings::new();
         ppu_texture_settings.set_filter(ogl::Filter::Nearest);
-        let ppu_image = RgbaImage::new(nesppu::DISPLAY_WIDTH as u32, nesppu::DISPLAY_HEIGHT as u32);
+        let area = game.ppu().visible_area();
+        let ppu_image = RgbaImage::new(area.width as u32, area.height as u32);
         let ppu_texture = ogl::Texture::from_image(&ppu_image, &ppu_texture_settings);
-        let mut this = App {
+        let debug_image = RgbaImage::new(nesppu::DEBUG_VIEW_WIDTH as u32, nesppu::DEBUG_VIEW_HEIGHT as u32);
+        let debug_texture = ogl::Texture::from_image(&debug_image, &ppu_texture_settings);
+        App {
             gl: ogl::GlGraphics::new(opengl),
-            ppu: Default::default(),
+            game,
             ppu_image: ppu_image,
             ppu_texture: ppu_texture,
-            player_pos: Vec2f::new(nesppu::DISPLAY_WIDTH as f32 / 2.0, PLAYER_Y),
-            clouds: Vec::new(),
-            precipitation: Vec::new(),
-            caught_rain: 0,
-            lightning_color_cycle_timer: 0,
-            lightning_color_set: 0,
-            death_state: None,
-        };
-
-        this.reset();
-
-        this
-    }
-
-    fn reset(&mut self) {
-        self.ppu = Default::default();
-        self.player_pos = Vec2f::new(nesppu::DISPLAY_WIDTH as f32 / 2.0, PLAYER_Y);
-        self.clouds = Vec::new();
-        self.precipitation = Vec::new();
-        self.caught_rain = 0;
-        self.lightning_color_cycle_timer = 0;
-        self.lightning_color_set = 0;
-        self.death_state = None;
-
-        // Clear PPU
-        for y in 0..nesppu::TILEMAP_HEIGHT_IN_TILES {
-            for x in 0..nesppu::TILEMAP_WIDTH_IN_TILES {
-                self.ppu.set_tile(x, y, BLANK_PATTERN_NAME);
-                self.ppu.set_attribute(x, y, 0);
-            }
+            sprite_overflow: false,
+            palettes,
+            palette_index: 0,
+            debug_image,
+            debug_texture,
+            show_debug_view: false,
+            debug_view_palette: 0,
         }
+    }
 
-        // Load patterns
-        load_all_patterns(&mut self.ppu);
-
-        // Set background color to black
-        self.ppu.set_common_bg_color(BACKGROUND_COLOR);
-
-        // Set background palettes
-        self.ppu.set_bg_colors(NORMAL_GROUND_ATTRIB, NORMAL_GROUND_COLORS);
-        self.ppu.set_bg_colors(GRAYS_ATTRIB, GRAYS_COLORS);
+    fn toggle_debug_view(&mut self) {
+        self.show_debug_view = !self.show_debug_view;
+    }
 
-        // Set sprite palettes
-        self.ppu.set_sprite_colors(PLAYER_ATTRIB, PLAYER_COLORS);
-        self.ppu.set_sprite_colors(CLOUD_ATTRIB, CLOUD_COLORS);
-        self.ppu.set_sprite_colors(LIGHTNING_ATTRIB, LIGHTNING_COLOR_SETS[self.lightning_color_set]);
-        self.ppu.set_sprite_colors(RAIN_ATTRIB, RAIN_COLORS);
+    // Cycle through the 8 palettes for showing the pattern tables in the debug view
+    fn next_debug_view_palette(&mut self) {
+        self.debug_view_palette = (self.debug_view_palette + 1) % 8;
+        println!("Debug view patterns shown in {} palette {}",
+                 if self.debug_view_palette < 4 { "background" } else { "sprite" }, self.debug_view_palette % 4);
+    }
 
-        // Draw ground
-        for y in GROUND_Y_TILE+1..nesppu::DISPLAY_HEIGHT_IN_TILES {
-            for x in 0..nesppu::DISPLAY_WIDTH_IN_TILES {
-                self.ppu.set_tile(x, y, GROUND_PATTERN_NAME);
-                self.ppu.set_attribute(x, y, NORMAL_GROUND_ATTRIB);
-            }
-        }
+    // For isolating layers while debugging
+    fn toggle_mask_bits(&mut self, bits: u8, description: &str) {
+        let ppu = self.game.ppu_mut();
+        let mask = ppu.mask() ^ bits;
+        ppu.set_mask(mask);
+        println!("{}: {}", description, if mask & bits != 0 { "on" } else { "off" });
+    }
 
-        // Draw top of ground
-        for x in 0..nesppu::DISPLAY_WIDTH_IN_TILES {
-            self.ppu.set_tile(x, GROUND_Y_TILE, GROUND_TOP_PATTERN_NAME);
-            self.ppu.set_attribute(x, GROUND_Y_TILE, NORMAL_GROUND_ATTRIB);
-        }
+    fn select_palette(&mut self, index: usize) {
+        self.palette_index = index;
+        let (ref name, ref palette) = self.palettes[index];
+        self.game.ppu_mut().set_system_palette(palette.clone());
+        println!("Palette: {}{}", name, if palette.has_emphasis() { " (with emphasis)" } else { "" });
+    }
 
-        // Spawn the first cloud
-        self.spawn_cloud(Vec2f::new(50.0, 20.0));
+    fn next_palette(&mut self) {
+        let index = (self.palette_index + 1) % self.palettes.len();
+        self.select_palette(index);
     }
 
-    fn spawn_cloud(&mut self, pos: Vec2f) {
-        self.clouds.push(Cloud {
-            direction: if random_bool(2) { CloudDirection::Left } else { CloudDirection::Right },
-            pos: pos,
-            speed: CLOUD_SPEED,
-            lightning_n: 4, // TODO: configurable lightning frequency
-            precipitation_period: PRECIPITATION_PERIOD, // TODO: configurable precipitation period
-            timer: 0,
-        });
+    fn toggle_hardware_sprite_limits(&mut self) {
+        let ppu = self.game.ppu_mut();
+        let limits = match ppu.sprite_limits() {
+            SpriteLimits::Extended => SpriteLimits::Hardware,
+            SpriteLimits::Hardware => SpriteLimits::Extended,
+        };
+        ppu.set_sprite_limits(limits);
+        println!("Sprite limits: {:?}", limits);
     }
 
-    fn spawn_lightning(&mut self, pos: Vec2f) {
-        self.precipitation.push(Precipitation {
-            type_: PrecipitationType::Lightning,
-            pos: pos,
-            timer: 0,
-        });
+    fn toggle_sprite_flicker(&mut self) {
+        let ppu = self.game.ppu_mut();
+        let flicker = !ppu.sprite_flicker();
+        ppu.set_sprite_flicker(flicker);
+        println!("Sprite flicker: {}", if flicker { "on" } else { "off" });
     }
 
-    fn spawn_raindrop(&mut self, pos: Vec2f) {
-        self.precipitation.push(Precipitation {
-            type_: PrecipitationType::Rain,
-            pos: pos,
-            timer: 0,
-        });
+    // Note: update must be called once every 60th of a second.
+    //       It will not check the time for accuracy.
+    fn update(&mut self, args: &UpdateArgs, frame: usize, direction: Vec2f) {
+        advance_game(&mut self.game, frame, direction)
     }
 
-    // This function exists because self.precipitation.drain_filter cannot be run
-    // in advance_frame_playing. The only way to coax the borrow checker to accept
-    // this code is to split out the fields from App and pass them to this function.
-    //
-    // Thanks a lot, Rust.
-    fn run_precipitation(
-        precipitation: &mut Vec<Precipitation>,
-        caught_rain: &mut u32,
-        death_state: &mut Option<DeathState>,
-        player_pos: &mut Vec2f,
-        sprite_index: &mut usize,
-        ppu: &mut Ppu
-    ) -> u32 {
-        // Simulate precipitation
-        let mut num_new_clouds = 0;
-        precipitation.drain_filter(|p| {
-            if let PrecipitationType::RainSplash = p.type_ {
-                p.timer += 1;
-                if p.timer >= RAINSPLASH_TIME {
-                    return true;
-                }
+    fn render(&mut self, args: &RenderArgs) {
+        // XXX: WHY do I have to borrow these variables out here? Why can't I just use them
+        //      directly in the closure?
+        let ppu = self.game.ppu();
+        let mut ppu_image = &mut self.ppu_image; // XXX: WHY do I have to say "let mut" here...
+        let ppu_texture = &mut self.ppu_texture; //     <- ...but not here?
+        let debug_image = &mut self.debug_image;
+        let debug_texture = &mut self.debug_texture;
+        let show_debug_view = self.show_debug_view;
+        let debug_view_palette = self.debug_view_palette;
+
+        let status = self.gl.draw(args.viewport(), |c, gl| {
+            let status = ppu.draw_image(&mut ppu_image);
+            let image_rect = Image::new().rect([0.0, 0.0, args.window_size[0], args.window_size[1]]);
+            if show_debug_view {
+                ppu.draw_debug_view(debug_image, debug_view_palette);
+                debug_texture.update(debug_image);
+                image_rect.draw(debug_texture, &Default::default(), c.transform, gl);
             } else {
-                p.pos.y += RAINFALL_SPEED;
-                if p.pos.y > GROUND_Y - 8.0 {
-                    p.pos.y = GROUND_Y - 8.0;
-                    if let PrecipitationType::Rain = p.type_ {
-                        p.type_ = PrecipitationType::RainSplash;
-                        p.timer = 0;
-                    } else {
-                        return true;
-                    }
-                }
+                ppu_texture.update(ppu_image);
+                image_rect.draw(ppu_texture, &Default::default(), c.transform, gl);
             }
+            status
+        });
 
-            // Check if player caught rain/lightning
-
-            // Compute player hitbox
-            let (pl, pt, pr, pb) = (player_pos.x, player_pos.y,
-                                    player_pos.x + 8.0, player_pos.y + 8.0);
-
-            // Compute rain hitbox (or hitPOINT, really.)
-            let (rx, ry) = (p.pos.x + 4.0, p.pos.y + 8.0);
-
-            // Check for hit
-            if rx >= pl && rx <= pr && ry >= pt && ry <= pb {
-                if let PrecipitationType::Rain = p.type_ {
-                    *caught_rain += 1;
-                    if *caught_rain % NEW_CLOUD_SCORE == 0 {
-                        num_new_clouds += 1;
-                    }
-                    return true;
-                } else if let PrecipitationType::Lightning = p.type_ {
-                    // NOTE: this isn't elegant, but setting DeathState will trigger the death
-                    //       sequence on the next frame.
-                    *death_state = Some(DeathState::default());
-                }
+        // Only report changes, rather than every frame
+        if status.sprite_overflow != self.sprite_overflow {
+            self.sprite_overflow = status.sprite_overflow;
+            if status.sprite_overflow {
+                println!("Sprite overflow: some sprites are not being drawn");
+            } else {
+                println!("Sprite overflow cleared");
             }
+        }
+    }
+}
 
-            let (pattern_name, attrib) = match p.type_ {
-                PrecipitationType::Rain => (RAIN_PATTERN_NAME, RAIN_ATTRIB),
-                PrecipitationType::RainSplash => (RAINSPLASH_PATTERN_NAME, RAIN_ATTRIB),
-                PrecipitationType::Lightning => (LIGHTNING_PATTERN_NAME, LIGHTNING_ATTRIB),
-            };
-            ppu.set_sprite(*sprite_index, p.pos.x as i32, p.pos.y as i32, pattern_name, attrib);
-            *sprite_index += 1;
+// Runs one frame of the game. The frame the player dies on is logged, so that a run and
+// its replay can be compared.
+fn advance_game(game: &mut Game, frame: usize, direction: Vec2f) {
+    let was_dead = game.is_dead();
+    game.advance_frame(direction);
+    if !was_dead && game.is_dead() {
+        println!("Struck by lightning on frame {} with {} rain caught", frame, game.caught_rain());
+    }
+}
 
-            return false;
-        });
+// The built-in palette, followed by every .pal file in dir. Files that can't be loaded are skipped.
+fn load_palettes(dir: &str) -> Vec<(String, SystemPalette)> {
+    let mut palettes = vec![("built-in".to_string(), SystemPalette::default())];
+
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.retain(|path| path.extension() == Some(OsStr::new("pal")));
+    paths.sort();
+
+    for path in paths {
+        match SystemPalette::load(&path) {
+            Ok(palette) => palettes.push((path.display().to_string(), palette)),
+            Err(e) => eprintln!("Skipping palette {}: {}", path.display(), e),
+        }
+    }
+    palettes
+}
 
-        num_new_clouds
+// Writes the built-in patterns to a CHR file, as a starting point for editing them.
+fn export_chr(path: &str) {
+    let game = Game::new(0);
+    match fs::write(path, game.ppu().chr()) {
+        Ok(()) => println!("Saved patterns to {}", path),
+        Err(e) => fatal_error(&format!("Could not save CHR file {}: {}", path, e)),
     }
+}
 
-    fn advance_frame_playing(&mut self, direction: Vec2f) {
-        // Move the player
-        self.player_pos.x += direction.x * PLAYER_SPEED;
-
-        // Drive lightning color cycles
-        self.lightning_color_cycle_timer += 1;
-        if self.lightning_color_cycle_timer >= LIGHTNING_COLOR_CYCLE_TIME {
-            self.lightning_color_cycle_timer = 0;
-            self.lightning_color_set += 1;
-            if self.lightning_color_set >= NUM_LIGHTNING_COLOR_SETS {
-                self.lightning_color_set = 0;
-            }
+// Plays a replay back as fast as possible, without opening a window.
+fn run_headless(replay: &Replay) {
+    let mut game = Game::new(replay.seed());
+    for frame in 0..replay.num_frames() {
+        advance_game(&mut game, frame, replay.input(frame).unwrap());
+    }
+    println!("Replay finished after {} frames with {} rain caught",
+             replay.num_frames(), game.caught_rain());
+}
 
-            self.ppu.set_sprite_colors(LIGHTNING_ATTRIB,
-                                       LIGHTNING_COLOR_SETS[self.lightning_color_set]);
-        }
+struct Options {
+    seed: Option<u64>,
+    record_path: Option<String>,
+    replay_path: Option<String>,
+    headless: bool,
+    hardware_sprites: bool,
+    sprite_flicker: bool,
+    overscan: Overscan,
+    palette_path: Option<String>,
+    chr_path: Option<String>,
+    tilesheet_path: Option<String>,
+    export_chr_path: Option<String>,
+    render_threads: usize,
+}
 
-        self.ppu.clear_sprites();
+// The window is 1024x768 for the full 256x240 display. Other visible areas keep the same scale.
+const WINDOW_SCALE_X: f64 = 4.0;
+const WINDOW_SCALE_Y: f64 = 3.2;
 
-        let mut sprite_index = 0;
+// Palettes that can be switched between with F4
+const PALETTE_DIR: &str = "palettes";
 
-        self.draw_player(&mut sprite_index);
+const USAGE: &str = concat!(
+    "Usage: lightning-dodge [--seed N] [--record FILE] [--replay FILE [--headless]]\n",
+    "                       [--hardware-sprites] [--flicker] [--overscan ntsc|pal|X,Y,W,H]\n",
+    "                       [--palette FILE] [--chr FILE] [--tilesheet FILE] [--render-threads N]\n",
+    "       lightning-dodge --export-chr FILE");
 
-        // Simulate clouds
-        for cn in 0..self.clouds.len() {
-            let mut cloud = &mut self.clouds[cn];
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
 
-            cloud.pos.x += match cloud.direction {
-                CloudDirection::Left => -cloud.speed,
-                CloudDirection::Right => cloud.speed,
-            };
+fn fatal_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
 
-            if cloud.pos.x < CLOUD_LEFT_BOUND {
-                cloud.pos.x = CLOUD_LEFT_BOUND;
-                cloud.direction = CloudDirection::Right;
-            } else if cloud.pos.x > CLOUD_RIGHT_BOUND {
-                cloud.pos.x = CLOUD_RIGHT_BOUND;
-                cloud.direction = CloudDirection::Left;
+fn parse_overscan(value: &str) -> Overscan {
+    match value {
+        "ntsc" => Overscan::Ntsc,
+        "pal" => Overscan::Pal,
+        _ => {
+            let numbers: Vec<usize> = value.split(',').map(|n| n.parse())
+                .collect::<Result<_, _>>()
+                .unwrap_or_else(|_| usage_error("--overscan must be ntsc, pal or X,Y,W,H"));
+            if numbers.len() != 4 {
+                usage_error("--overscan must be ntsc, pal or X,Y,W,H");
             }
-
-            self.ppu.set_sprite(sprite_index, cloud.pos.x as i32, cloud.pos.y as i32,
-                                CLOUD_LEFT_PATTERN_NAME, CLOUD_ATTRIB);
-            sprite_index += 1;
-            self.ppu.set_sprite(sprite_index, cloud.pos.x as i32 + 8, cloud.pos.y as i32,
-                                CLOUD_RIGHT_PATTERN_NAME, CLOUD_ATTRIB);
-            sprite_index += 1;
-
-            // Spawn new raindrops and/or lightning bolts
-            cloud.timer += 1;
-            if cloud.timer >= cloud.precipitation_period {
-                cloud.timer = 0;
-                if random_bool(cloud.lightning_n) {
-                    let pos = cloud.pos;
-                    self.spawn_lightning(pos);
-                } else {
-                    let pos = cloud.pos;
-                    self.spawn_raindrop(pos);
-                }
+            let rect = DisplayRect { x: numbers[0], y: numbers[1], width: numbers[2], height: numbers[3] };
+            if rect.width == 0 || rect.height == 0 ||
+               rect.x + rect.width > nesppu::DISPLAY_WIDTH || rect.y + rect.height > nesppu::DISPLAY_HEIGHT {
+                usage_error(&format!("--overscan must be inside the {}x{} display",
+                                     nesppu::DISPLAY_WIDTH, nesppu::DISPLAY_HEIGHT));
             }
-        }
+            Overscan::Custom(rect)
+        },
+    }
+}
 
-        let num_new_clouds = App::run_precipitation(
-            &mut self.precipitation,
-            &mut self.caught_rain,
-            &mut self.death_state,
-            &mut self.player_pos,
-            &mut sprite_index,
-            &mut self.ppu
-        );
-
-        // Spawn new clouds if score increased enough
-        for _ in 0..num_new_clouds {
-            let x = random_f32(CLOUD_LEFT_BOUND, CLOUD_RIGHT_BOUND);
-            let y = random_f32(10.0, 60.0);
-            self.spawn_cloud(Vec2f::new(x, y));
+fn parse_args() -> Options {
+    let mut options = Options {
+        seed: None,
+        record_path: None,
+        replay_path: None,
+        headless: false,
+        hardware_sprites: false,
+        sprite_flicker: false,
+        overscan: Overscan::Pal,
+        palette_path: None,
+        chr_path: None,
+        tilesheet_path: None,
+        export_chr_path: None,
+        render_threads: 1,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--seed requires a value"));
+                let seed = value.parse().unwrap_or_else(|_| usage_error("--seed must be an unsigned integer"));
+                options.seed = Some(seed);
+            },
+            "--record" => {
+                options.record_path = Some(args.next().unwrap_or_else(|| usage_error("--record requires a file")));
+            },
+            "--replay" => {
+                options.replay_path = Some(args.next().unwrap_or_else(|| usage_error("--replay requires a file")));
+            },
+            "--headless" => options.headless = true,
+            "--hardware-sprites" => options.hardware_sprites = true,
+            "--flicker" => options.sprite_flicker = true,
+            "--overscan" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--overscan requires a value"));
+                options.overscan = parse_overscan(&value);
+            },
+            "--chr" => {
+                options.chr_path = Some(args.next().unwrap_or_else(|| usage_error("--chr requires a file")));
+            },
+            "--tilesheet" => {
+                options.tilesheet_path = Some(args.next().unwrap_or_else(|| usage_error("--tilesheet requires a file")));
+            },
+            "--export-chr" => {
+                options.export_chr_path = Some(args.next().unwrap_or_else(|| usage_error("--export-chr requires a file")));
+            },
+            "--render-threads" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--render-threads requires a value"));
+                options.render_threads = match value.parse() {
+                    Ok(n) if n >= 1 => n,
+                    _ => usage_error("--render-threads must be a positive integer"),
+                };
+            },
+            "--palette" => {
+                options.palette_path = Some(args.next().unwrap_or_else(|| usage_error("--palette requires a file")));
+            },
+            _ => usage_error(&format!("Unknown argument: {}", arg)),
         }
-
-        self.draw_scorebar();
     }
 
-    fn advance_frame_death(&mut self, direction: Vec2f) {
-        self.ppu.clear_sprites();
-
-        let mut sprite_index = 0;
-
-        self.draw_player(&mut sprite_index);
-
-        self.draw_scorebar();
+    if options.replay_path.is_some() && options.seed.is_some() {
+        usage_error("--seed cannot be used with --replay; the replay has its own seed");
+    }
+    if options.headless && options.replay_path.is_none() {
+        usage_error("--headless requires --replay");
+    }
 
-        let death_state = self.death_state.as_mut().unwrap();
+    options
+}
 
-        if death_state.timer % 30 < 15 && death_state.timer < 2 * 60 {
-            self.ppu.set_common_bg_color(0x20);
-        } else {
-            self.ppu.set_common_bg_color(BACKGROUND_COLOR);
-        }
+fn main() {
+    let options = parse_args();
 
-        death_state.timer += 1;
-        if death_state.timer >= DEATH_TIME {
-            // Reset the game
-            self.reset();
-        }
+    if let Some(ref path) = options.export_chr_path {
+        export_chr(path);
+        return;
     }
 
-    fn advance_frame(&mut self, direction: Vec2f) {
-        let mut direction = direction;
-        if direction.magnitude2() > 1.0 {
-            direction = direction.normalize();
-        }
+    let playback = options.replay_path.as_ref().map(|path| {
+        Replay::load(path).unwrap_or_else(|e| fatal_error(&format!("Could not load replay {}: {}", path, e)))
+    });
 
-        if self.death_state.is_some() {
-            self.advance_frame_death(direction);
-        } else {
-            self.advance_frame_playing(direction);
-        }
+    if options.headless {
+        run_headless(playback.as_ref().unwrap());
+        return;
     }
 
-    fn draw_player(&mut self, sprite_index: &mut usize) {
-        // Draw player
-        self.ppu.set_sprite(*sprite_index, self.player_pos.x as i32, self.player_pos.y as i32,
-                            PLAYER_PATTERN_NAME, PLAYER_ATTRIB);
-        *sprite_index += 1;
-        self.ppu.set_sprite(*sprite_index, self.player_pos.x as i32, self.player_pos.y as i32 - 8,
-                            PLAYER_TOP_PATTERN_NAME, PLAYER_ATTRIB);
-        *sprite_index += 1;
+    // Pick a seed at random unless one was given, and report it so the run can be reproduced.
+    let seed = match playback {
+        Some(ref replay) => replay.seed(),
+        None => options.seed.unwrap_or_else(|| rand::thread_rng().gen()),
+    };
+    println!("Seed: {}", seed);
+
+    let mut game = Game::new(seed);
+    let mut recording = options.record_path.as_ref().map(|_| Replay::new(game.seed()));
+    if let Some(ref path) = options.chr_path {
+        game.load_chr(path).unwrap_or_else(|e| fatal_error(&format!("Could not load CHR file {}: {}", path, e)));
     }
-
-    fn draw_scorebar(&mut self) {
-        for x in 0..nesppu::DISPLAY_WIDTH_IN_TILES {
-            self.ppu.set_tile(x, nesppu::DISPLAY_HEIGHT_IN_TILES - 1, BLANK_PATTERN_NAME);
-            self.ppu.set_attribute(x, nesppu::DISPLAY_HEIGHT_IN_TILES - 1, GRAYS_ATTRIB);
-        }
-
-        let mut score = self.caught_rain;
-        let mut score_x = nesppu::DISPLAY_WIDTH_IN_TILES - 1;
-        let mut draw_score = true;
-        while draw_score {
-            let digit = score % 10;
-            self.ppu.set_tile(score_x, nesppu::DISPLAY_HEIGHT_IN_TILES - 1,
-                              NUM0_PATTERN_NAME + digit as u8);
-            score /= 10;
-            score_x -= 1;
-            draw_score = score != 0;
-        }
+    if let Some(ref path) = options.tilesheet_path {
+        game.load_tilesheet(path).unwrap_or_else(|e| fatal_error(&format!("Could not load tilesheet {}: {}", path, e)));
     }
-
-    // Note: update must be called once every 60th of a second.
-    //       It will not check the time for accuracy.
-    fn update(&mut self, args: &UpdateArgs, direction: Vec2f) {
-        self.advance_frame(direction)
+    if let Err(e) = game.set_overscan(options.overscan) {
+        usage_error(&format!("Bad --overscan: {}", e));
     }
-
-    fn render(&mut self, args: &RenderArgs) {
-        // XXX: WHY do I have to borrow these variables out here? Why can't I just use them
-        //      directly in the closure?
-        let ppu = &self.ppu;
-        let mut ppu_image = &mut self.ppu_image; // XXX: WHY do I have to say "let mut" here...
-        let ppu_texture = &mut self.ppu_texture; //     <- ...but not here?
-
-        self.gl.draw(args.viewport(), |c, gl| {
-            ppu.draw_image(&mut ppu_image);
-            ppu_texture.update(ppu_image);
-            let image_rect = Image::new().rect([0.0, 0.0, args.window_size[0], args.window_size[1]]);
-            image_rect.draw(ppu_texture, &Default::default(), c.transform, gl);
-        });
+    if options.hardware_sprites {
+        game.ppu_mut().set_sprite_limits(SpriteLimits::Hardware);
+    }
+    game.ppu_mut().set_sprite_flicker(options.sprite_flicker);
+    game.ppu_mut().set_render_threads(options.render_threads);
+
+    let mut palettes = load_palettes(PALETTE_DIR);
+    if let Some(ref path) = options.palette_path {
+        let palette = SystemPalette::load(path)
+            .unwrap_or_else(|e| fatal_error(&format!("Could not load palette {}: {}", path, e)));
+        palettes.push((path.clone(), palette));
     }
-}
 
-fn main() {
+    let area = game.ppu().visible_area();
+    let window_size = [(area.width as f64 * WINDOW_SCALE_X) as u32,
+                       (area.height as f64 * WINDOW_SCALE_Y) as u32];
+
     let opengl = ogl::OpenGL::V3_2;
 
     let glutin_window: Window = WindowSettings::new(
-            "Lightning Dodge",
-            [1024, 768]
+            format!("Lightning Dodge (seed {})", seed),
+            window_size
         )
         .exit_on_esc(true)
         .build()
@@ -465,13 +419,19 @@ fn main() {
 
     let window: PistonWindow = PistonWindow::new(opengl, 1, glutin_window);
 
-    let mut app = App::new(opengl);
+    let mut app = App::new(opengl, game, palettes);
+    if options.palette_path.is_some() {
+        let index = app.palettes.len() - 1;
+        app.select_palette(index);
+    }
 
     let mut left_state = false;
     let mut right_state = false;
     let mut up_state = false;
     let mut down_state = false;
 
+    let mut frame = 0;
+
     let mut events = window.max_fps(60).ups(60);
     while let Some(e) = events.next() {
         if let Some(r) = e.render_args() {
@@ -479,21 +439,39 @@ fn main() {
         }
 
         if let Some(u) = e.update_args() {
-            let mut direction = Vec2f::zero();
-            if left_state {
-                direction.x -= 1.0
-            }
-            if right_state {
-                direction.x += 1.0
-            }
-            if up_state {
-                direction.y -= 1.0
-            }
-            if down_state {
-                direction.y += 1.0
+            let direction = match playback {
+                Some(ref replay) => match replay.input(frame) {
+                    Some(direction) => direction,
+                    None => {
+                        println!("Replay finished after {} frames with {} rain caught",
+                                 frame, app.game.caught_rain());
+                        break;
+                    },
+                },
+                None => {
+                    let mut direction = Vec2f::zero();
+                    if left_state {
+                        direction.x -= 1.0
+                    }
+                    if right_state {
+                        direction.x += 1.0
+                    }
+                    if up_state {
+                        direction.y -= 1.0
+                    }
+                    if down_state {
+                        direction.y += 1.0
+                    }
+                    direction
+                },
+            };
+
+            if let Some(ref mut recording) = recording {
+                recording.record(direction);
             }
 
-            app.update(&u, direction);
+            app.update(&u, frame, direction);
+            frame += 1;
         }
 
         if let Some(Button::Keyboard(key)) = e.press_args() {
@@ -502,6 +480,14 @@ fn main() {
                 Key::Right => right_state = true,
                 Key::Up => up_state = true,
                 Key::Down => down_state = true,
+                Key::F2 => app.toggle_hardware_sprite_limits(),
+                Key::F3 => app.toggle_sprite_flicker(),
+                Key::F4 => app.next_palette(),
+                Key::F5 => app.toggle_mask_bits(MASK_SHOW_BG, "Background"),
+                Key::F6 => app.toggle_mask_bits(MASK_SHOW_SPRITES, "Sprites"),
+                Key::Tab => app.toggle_debug_view(),
+                Key::P => app.next_debug_view_palette(),
+                Key::F7 => app.toggle_mask_bits(MASK_SHOW_BG_LEFT | MASK_SHOW_SPRITES_LEFT, "Leftmost 8 pixels"),
                 _ => {},
             }
         }
@@ -516,4 +502,11 @@ fn main() {
             }
         }
     }
+
+    if let (Some(path), Some(recording)) = (options.record_path, recording) {
+        match recording.save(&path) {
+            Ok(()) => println!("Saved {} frames of input to {}", recording.num_frames(), path),
+            Err(e) => fatal_error(&format!("Could not save replay {}: {}", path, e)),
+        }
+    }
 }
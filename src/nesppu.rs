@@ -1,20 +1,37 @@
 // NES/Famicom PPU-like rendering module.
 // Tries to emulate the quirks and limitations of the original NES/Famicom
-// pixel processing unit. One exception is that more sprites are supported.
+// pixel processing unit. One exception is that more sprites are supported,
+// unless the hardware sprite limits are turned on.
 
 extern crate image;
 
-use image::{Rgba, RgbaImage, Pixel};
+use image::{Rgba, RgbaImage};
 
-// FIXME: NTSC hardware had only 224 lines. The top and bottom 8 pixels were cut off. (??? TODO: verify)
+use std::cmp;
+use std::fs;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
+use std::thread;
+
+mod debug;
+mod registers;
+
+pub use self::debug::{DEBUG_VIEW_WIDTH, DEBUG_VIEW_HEIGHT};
+pub use self::registers::VramIncrement;
+
+// The PPU always draws 240 lines, but NTSC TVs typically cut off the top and bottom 8 lines.
+// draw_image only outputs the part of the display selected by the Overscan setting.
 pub const DISPLAY_WIDTH: usize = 256;
 pub const DISPLAY_HEIGHT: usize = 240;
 
-//pub const NUM_SPRITES: usize = 64;
-//pub const MAX_SPRITES_ON_LINE: usize = 8;
 // XXX: sprite capabilities are dramatically increased from the original hardware!
 pub const NUM_SPRITES: usize = 1024;
 pub const MAX_SPRITES_ON_LINE: usize = 256;
+// The original hardware's limits, enforced with SpriteLimits::Hardware.
+pub const HARDWARE_NUM_SPRITES: usize = 64;
+pub const HARDWARE_MAX_SPRITES_ON_LINE: usize = 8;
 
 // A tile is also known as a "pattern" in NES-dev parlance.
 // A tilemap is also known as a "nametable".
@@ -22,12 +39,13 @@ pub const MAX_SPRITES_ON_LINE: usize = 256;
 // there was only enough VRAM for 2 screens. Depending on the cartridge hardware, the
 // NES's tilemap had a mirrored arrangement where the screens were duplicated horizontally
 // or vertically. Alternately, the cartridge could include its own VRAM to extend the tilemap
-// to the full size.
+// to the full size. See Mirroring. Either way, scrolling wraps around at the edges of the tilemap.
 pub const TILE_WIDTH_IN_PIXELS: usize = 8;
 pub const TILE_HEIGHT_IN_PIXELS: usize = 8;
 pub const TILEMAP_WIDTH_IN_TILES: usize = 64;
 pub const TILEMAP_HEIGHT_IN_TILES: usize = 60;
 pub const PATTERN_SIZE_IN_BYTES: usize = 16;
+pub const PATTERNS_PER_TABLE: usize = 256;
 
 pub const DISPLAY_WIDTH_IN_TILES: usize = DISPLAY_WIDTH / TILE_WIDTH_IN_PIXELS;
 pub const DISPLAY_HEIGHT_IN_TILES: usize = DISPLAY_HEIGHT / TILE_HEIGHT_IN_PIXELS;
@@ -36,18 +54,56 @@ const TILEMAP_SIZE_IN_BYTES: usize = TILEMAP_WIDTH_IN_TILES * TILEMAP_HEIGHT_IN_
 const ATTRMAP_WIDTH_IN_METATILES: usize = TILEMAP_WIDTH_IN_TILES / 2;
 const ATTRMAP_HEIGHT_IN_METATILES: usize = TILEMAP_HEIGHT_IN_TILES / 2;
 const ATTRMAP_SIZE_IN_BYTES: usize = ATTRMAP_WIDTH_IN_METATILES * ATTRMAP_HEIGHT_IN_METATILES;
-const PATTERN_TABLE_SIZE_IN_BYTES: usize = 0x2000;
+
+// NES CHR data: 16 bytes per pattern, as 8 bytes of bit 0 of each pixel and then 8 bytes of bit 1,
+// one byte per row with the leftmost pixel in bit 7. A CHR file holds both pattern tables, or
+// just the first one.
+pub const CHR_PATTERN_SIZE_IN_BYTES: usize = 16;
+pub const CHR_SIZE_IN_BYTES: usize = 2 * PATTERNS_PER_TABLE * CHR_PATTERN_SIZE_IN_BYTES;
+
+// CHR bank switching, as done by NES mappers. The patterns live in CHR memory, which is made of
+// 1 KiB banks of 64 patterns each and can be larger than the pattern tables. The two pattern
+// tables are split into NUM_CHR_WINDOWS 1 KiB windows, and each window shows one bank. By
+// default CHR memory is just big enough for both pattern tables, and window N shows bank N.
+pub const PATTERNS_PER_CHR_BANK: usize = 64;
+pub const CHR_BANK_SIZE_IN_BYTES: usize = PATTERNS_PER_CHR_BANK * CHR_PATTERN_SIZE_IN_BYTES;
+pub const NUM_CHR_WINDOWS: usize = 2 * PATTERNS_PER_TABLE / PATTERNS_PER_CHR_BANK;
+const PATTERN_BANK_SIZE_IN_BYTES: usize = PATTERNS_PER_CHR_BANK * PATTERN_SIZE_IN_BYTES;
 const PALETTE_SIZE_IN_BYTES: usize = 0x20;
 
+// Sprite attribute bits, laid out like byte 2 of a sprite in NES OAM.
+pub const SPRITE_PALETTE_MASK: u8 = 0x03;
+pub const SPRITE_BEHIND_BG: u8 = 0x20;
+pub const SPRITE_FLIP_HORIZ: u8 = 0x40;
+pub const SPRITE_FLIP_VERT: u8 = 0x80;
+
+// Mask bits, laid out like the NES's PPUMASK register.
+pub const MASK_GREYSCALE: u8 = 0x01;
+pub const MASK_SHOW_BG_LEFT: u8 = 0x02; // Show the background in the leftmost 8 pixels
+pub const MASK_SHOW_SPRITES_LEFT: u8 = 0x04; // Show sprites in the leftmost 8 pixels
+pub const MASK_SHOW_BG: u8 = 0x08;
+pub const MASK_SHOW_SPRITES: u8 = 0x10;
+pub const MASK_EMPHASIZE_RED: u8 = 0x20;
+pub const MASK_EMPHASIZE_GREEN: u8 = 0x40;
+pub const MASK_EMPHASIZE_BLUE: u8 = 0x80;
+pub const MASK_EMPHASIS: u8 = MASK_EMPHASIZE_RED | MASK_EMPHASIZE_GREEN | MASK_EMPHASIZE_BLUE;
+pub const MASK_SHOW_ALL: u8 = MASK_SHOW_BG_LEFT | MASK_SHOW_SPRITES_LEFT | MASK_SHOW_BG | MASK_SHOW_SPRITES;
+
+// Width of the column at the left edge of the display that MASK_SHOW_*_LEFT apply to
+const CLIP_LEFT_WIDTH: usize = 8;
+
+// How much emphasis darkens the colors that aren't emphasized
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
 #[derive(Copy, Clone)]
 struct Sprite {
     x: u8,
     y: u8,
     tile: u8,
-    attrib: u8,
-    // TODO: priority, size
-    flip_horiz: bool, // TODO: implement
-    flip_vert: bool, // TODO: implement
+    attrib: u8, // Palette number only; the flags are stored separately.
+    behind_bg: bool,
+    flip_horiz: bool,
+    flip_vert: bool,
 }
 
 impl Default for Sprite {
@@ -57,20 +113,318 @@ impl Default for Sprite {
             y: 255, // Sprites at line 255 are effectively disabled.
             tile: 0,
             attrib: 0,
+            behind_bg: false,
             flip_horiz: false,
             flip_vert: false,
         }
     }
 }
 
+// A rectangle of the display, in pixels
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DisplayRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// The pixel formats draw_pixels can write
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PixelFormat {
+    // One byte per pixel: the color (0x00..0x3F), after greyscale. Emphasis applies to the whole
+    // frame, so it isn't included; get_color_rgba(color, mask()) gives the RGBA of a pixel.
+    Indexed,
+    // Four bytes per pixel, in byte order, as draw_image writes
+    Rgba8888,
+    Bgra8888,
+    // Two bytes per pixel, little-endian, with red in the top 5 bits
+    Rgb565,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Indexed => 1,
+            PixelFormat::Rgba8888 | PixelFormat::Bgra8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+}
+
+// Which part of the display is visible
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Overscan {
+    // 256x224, with the top and bottom 8 lines cut off like on a typical NTSC TV
+    Ntsc,
+    // The full 256x240 display
+    Pal,
+    // Any rectangle inside the display
+    Custom(DisplayRect),
+}
+
+impl Overscan {
+    pub fn visible_area(&self) -> DisplayRect {
+        match *self {
+            Overscan::Ntsc => DisplayRect { x: 0, y: 8, width: DISPLAY_WIDTH, height: DISPLAY_HEIGHT - 16 },
+            Overscan::Pal => DisplayRect { x: 0, y: 0, width: DISPLAY_WIDTH, height: DISPLAY_HEIGHT },
+            Overscan::Custom(rect) => rect,
+        }
+    }
+}
+
+// Which of the tilemap's four screens share VRAM. The tilemap is stored as 64x60 tiles; the
+// screens that share VRAM use the storage of the top-left screen ("page 0") or the top-right
+// screen ("page 1"), like the NES's two pages of nametable RAM.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mirroring {
+    // The left and right screens are the same. Used for vertical scrolling.
+    Horizontal,
+    // The top and bottom screens are the same. Used for horizontal scrolling.
+    Vertical,
+    // All four screens are page 0
+    SingleScreenLower,
+    // All four screens are page 1
+    SingleScreenUpper,
+    // Every screen is different, as if the cartridge had its own VRAM
+    FourScreen,
+}
+
+// Like bit 5 of PPUCTRL, this applies to all sprites at once.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SpriteSize {
+    Size8x8,
+    // Sprites are two tiles tall. Bit 0 of the tile number selects the pattern table, and the
+    // rest selects an even tile for the top half; the next tile is the bottom half.
+    Size8x16,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SpriteLimits {
+    // NUM_SPRITES sprites, MAX_SPRITES_ON_LINE per line
+    Extended,
+    // Only the first HARDWARE_NUM_SPRITES sprites exist, HARDWARE_MAX_SPRITES_ON_LINE per line
+    Hardware,
+}
+
+// The size of the windows and banks that set_chr_bank switches, like the CHR bank sizes of the
+// various NES mappers.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ChrBankSize {
+    Size1K,
+    Size2K,
+    Size4K,
+}
+
+impl ChrBankSize {
+    fn num_1k_banks(&self) -> usize {
+        match *self {
+            ChrBankSize::Size1K => 1,
+            ChrBankSize::Size2K => 2,
+            ChrBankSize::Size4K => 4,
+        }
+    }
+}
+
+// Which 1 KiB bank of CHR memory each window of the pattern tables shows
+type ChrBanks = [usize; NUM_CHR_WINDOWS];
+
+const DEFAULT_CHR_BANKS: ChrBanks = [0, 1, 2, 3, 4, 5, 6, 7];
+
+fn check_chr_window(size: ChrBankSize, window: usize) {
+    assert!(window < NUM_CHR_WINDOWS / size.num_1k_banks(), "CHR window {} out of range for {:?}", window, size);
+}
+
+// window and bank are counted in units of size
+fn map_chr_bank(chr_banks: &mut ChrBanks, size: ChrBankSize, window: usize, bank: usize) {
+    check_chr_window(size, window);
+    let n = size.num_1k_banks();
+    for (i, chr_bank) in chr_banks[window * n .. (window + 1) * n].iter_mut().enumerate() {
+        *chr_bank = bank * n + i;
+    }
+}
+
+// Information gathered while drawing a frame, like the NES's PPUSTATUS register.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct PpuStatus {
+    // Some line had more sprites than the line limit, and the extra sprites were not drawn.
+    // Unlike the NES, this is not subject to the hardware's overflow detection bug.
+    pub sprite_overflow: bool,
+    // The first line where an opaque pixel of sprite 0 overlapped an opaque background pixel.
+    // As on the NES, this never happens at x=255.
+    pub sprite_zero_hit_line: Option<usize>,
+}
+
+impl PpuStatus {
+    pub fn sprite_zero_hit(&self) -> bool {
+        self.sprite_zero_hit_line.is_some()
+    }
+}
+
+// A register change made partway through drawing a frame. Games use these for split-screen
+// effects, like a status bar that doesn't scroll with the playfield.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RasterEffect {
+    ScrollX(u32),
+    // Unlike on the NES, the new vertical scroll takes effect right away, as if the frame had
+    // started with it.
+    ScrollY(u32),
+    // Set palette RAM entry (0x00..0x1F, mirrored like the NES) to a color
+    PaletteColor(u8, u8),
+    // Switch a CHR bank, as with set_chr_bank: size, window, bank
+    ChrBank(ChrBankSize, usize, usize),
+}
+
+// The sprites on each line of a frame, as found by sprite evaluation
+struct SpriteLines {
+    indexes: Vec<u16>, // max_per_line entries for each line, frontmost sprite first
+    counts: [usize; DISPLAY_HEIGHT],
+    max_per_line: usize,
+    // Some line had more sprites than max_per_line
+    overflow: bool,
+}
+
+impl SpriteLines {
+    fn on_line(&self, y: usize) -> &[u16] {
+        let start = y * self.max_per_line;
+        &self.indexes[start .. start + self.counts[y]]
+    }
+}
+
+// What draw_image works out once per frame, shared by every line
+struct FrameSetup {
+    area: DisplayRect,
+    show_bg: bool,
+    show_sprites: bool,
+    show_bg_left: bool,
+    show_sprites_left: bool,
+    sprite_lines: SpriteLines,
+    format: PixelFormat,
+    // Each color's pixel in the output format, in the first bytes_per_pixel bytes.
+    // Greyscale and emphasis are already applied.
+    pixel_colors: [[u8; 4]; NUM_SYSTEM_COLORS],
+}
+
+impl FrameSetup {
+    // Convert a line of colors to the output format. Each pixel size has its own loop, so that
+    // every pixel is copied with a fixed-size copy.
+    fn encode_line(&self, line_colors: &[u8; DISPLAY_WIDTH], pixels: &mut [u8; 4 * DISPLAY_WIDTH]) {
+        let colors = &self.pixel_colors;
+        match self.format.bytes_per_pixel() {
+            1 => for (pixel, &color) in pixels.iter_mut().zip(line_colors.iter()) {
+                *pixel = colors[color as usize][0];
+            },
+            2 => for (pixel, &color) in pixels.chunks_exact_mut(2).zip(line_colors.iter()) {
+                pixel.copy_from_slice(&colors[color as usize][..2]);
+            },
+            _ => for (pixel, &color) in pixels.chunks_exact_mut(4).zip(line_colors.iter()) {
+                pixel.copy_from_slice(&colors[color as usize]);
+            },
+        }
+    }
+}
+
+// A line of the picture as palette RAM addresses, before it is looked up in the palette.
+// Background pixels with pixel value 0 are at address 0, and sprite addresses of 0 are transparent.
+struct Line {
+    bg: [u8; DISPLAY_WIDTH],
+    // The frontmost opaque sprite pixel, and whether that sprite is behind the background
+    sprites: [u8; DISPLAY_WIDTH],
+    sprites_behind_bg: [bool; DISPLAY_WIDTH],
+    // Where sprite 0 is opaque, even if other sprites cover it
+    sprite_zero: [bool; DISPLAY_WIDTH],
+}
+
+impl Default for Line {
+    fn default() -> Self {
+        Line {
+            bg: [0; DISPLAY_WIDTH],
+            sprites: [0; DISPLAY_WIDTH],
+            sprites_behind_bg: [false; DISPLAY_WIDTH],
+            sprite_zero: [false; DISPLAY_WIDTH],
+        }
+    }
+}
+
+impl Line {
+    fn bg_opaque(&self, dx: usize) -> bool {
+        self.bg[dx] & 0x3 != 0
+    }
+
+    fn clip_bg_left(&mut self) {
+        for address in &mut self.bg[..CLIP_LEFT_WIDTH] {
+            *address = 0;
+        }
+    }
+
+    fn clip_sprites_left(&mut self) {
+        for dx in 0..CLIP_LEFT_WIDTH {
+            self.sprites[dx] = 0;
+            self.sprite_zero[dx] = false;
+        }
+    }
+
+    // As on the NES, only the frontmost sprite's priority bit counts. A sprite behind the
+    // background hides any sprites behind it even where the background covers it.
+    fn palette_address(&self, dx: usize) -> u8 {
+        if self.sprites[dx] != 0 && (!self.sprites_behind_bg[dx] || !self.bg_opaque(dx)) {
+            self.sprites[dx]
+        } else {
+            self.bg[dx]
+        }
+    }
+}
+
+// The registers that raster effects can change, as they are while drawing one line.
+#[derive(Clone)]
+struct RasterState {
+    scroll_x: u32,
+    scroll_y: u32,
+    palette: [u8; PALETTE_SIZE_IN_BYTES],
+    chr_banks: ChrBanks,
+}
+
+impl RasterState {
+    fn apply(&mut self, effect: RasterEffect) {
+        match effect {
+            RasterEffect::ScrollX(x) => self.scroll_x = x,
+            RasterEffect::ScrollY(y) => self.scroll_y = y,
+            RasterEffect::PaletteColor(address, color) => self.palette[palette_ram_index(address as u16)] = color & 0x3f,
+            RasterEffect::ChrBank(size, window, bank) => map_chr_bank(&mut self.chr_banks, size, window, bank),
+        }
+    }
+
+}
+
 pub struct Ppu {
     tilemap: [u8; TILEMAP_SIZE_IN_BYTES],
     attrmap: [u8; ATTRMAP_SIZE_IN_BYTES],
-    pattern_table: [u8; PATTERN_TABLE_SIZE_IN_BYTES],
-    palette: [u8; PALETTE_SIZE_IN_BYTES], // Identical to Memory Map at <https://wiki.nesdev.com/w/index.php/PPU_palettes>
+    chr_memory: Vec<u8>, // Patterns, in the same format as get_pattern returns
+    decoded_chr: Vec<DecodedPattern>, // Every pattern in chr_memory, decoded. Kept up to date as patterns change.
+    chr_banks: ChrBanks,
+    palette: [u8; PALETTE_SIZE_IN_BYTES], // Identical to Memory Map at <https://wiki.nesdev.com/w/index.php/PPU_palettes>,
+                                          // except that the mirrored entries 0x10/0x14/0x18/0x1C are unused
     sprites: [Sprite; NUM_SPRITES],
+    sprite_size: SpriteSize,
+    bg_pattern_table: usize, // 0 or 1
+    sprite_pattern_table: usize, // 0 or 1. Not used for 8x16 sprites.
+    sprite_limits: SpriteLimits,
+    sprite_flicker: bool,
+    flicker_offset: usize, // Sprite evaluation starts at this sprite (modulo the sprites in use)
+    render_threads: usize,
+    mirroring: Mirroring,
+    overscan: Overscan,
     scroll_x: u32,
     scroll_y: u32,
+    raster_effects: Vec<(usize, RasterEffect)>, // Sorted by line
+    system_palette: SystemPalette,
+    mask: u8,
+    // Register interface state
+    vram_address: u16,
+    address_latch_low: bool, // The next PPUADDR write is the low byte
+    vram_increment: VramIncrement,
+    ppudata_read_buffer: u8,
+    oam_address: u8,
 }
 
 impl Default for Ppu {
@@ -78,22 +432,118 @@ impl Default for Ppu {
         Ppu {
             tilemap: [0; TILEMAP_SIZE_IN_BYTES],
             attrmap: [0; ATTRMAP_SIZE_IN_BYTES],
-            pattern_table: [0; PATTERN_TABLE_SIZE_IN_BYTES],
+            chr_memory: vec![0; NUM_CHR_WINDOWS * PATTERN_BANK_SIZE_IN_BYTES],
+            decoded_chr: vec![[[0; TILE_WIDTH_IN_PIXELS]; TILE_HEIGHT_IN_PIXELS]; NUM_CHR_WINDOWS * PATTERNS_PER_CHR_BANK],
+            chr_banks: DEFAULT_CHR_BANKS,
             palette: [0; PALETTE_SIZE_IN_BYTES],
             sprites: [Sprite::default(); NUM_SPRITES],
+            sprite_size: SpriteSize::Size8x8,
+            bg_pattern_table: 0,
+            sprite_pattern_table: 0,
+            sprite_limits: SpriteLimits::Extended,
+            sprite_flicker: false,
+            flicker_offset: 0,
+            render_threads: 1,
+            mirroring: Mirroring::FourScreen,
+            overscan: Overscan::Pal,
             scroll_x: 0,
             scroll_y: 0,
+            raster_effects: Vec::new(),
+            system_palette: SystemPalette::default(),
+            mask: MASK_SHOW_ALL,
+            vram_address: 0,
+            address_latch_low: false,
+            vram_increment: VramIncrement::Across,
+            ppudata_read_buffer: 0,
+            oam_address: 0,
+        }
+    }
+}
+
+const NTSC_PALETTE: &[u8; 64 * 3] = include_bytes!("ntscpalette.pal");
+
+pub const NUM_SYSTEM_COLORS: usize = 64;
+// Palettes with emphasis hold 8 copies of the 64 colors, one for each combination of emphasis bits
+pub const NUM_SYSTEM_COLORS_WITH_EMPHASIS: usize = NUM_SYSTEM_COLORS * 8;
+
+// The RGB colors the PPU's 64 color numbers are displayed as, in the 64 or 512 entry .pal
+// format used by most emulators: 3 bytes (R, G, B) per color.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SystemPalette {
+    colors: Vec<Rgba<u8>>,
+}
+
+impl Default for SystemPalette {
+    fn default() -> Self {
+        SystemPalette::from_pal_bytes(NTSC_PALETTE).unwrap()
+    }
+}
+
+impl SystemPalette {
+    pub fn from_pal_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() != NUM_SYSTEM_COLORS * 3 && bytes.len() != NUM_SYSTEM_COLORS_WITH_EMPHASIS * 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!(".pal file must be {} or {} bytes long, not {}",
+                        NUM_SYSTEM_COLORS * 3, NUM_SYSTEM_COLORS_WITH_EMPHASIS * 3, bytes.len())));
+        }
+        let colors = bytes.chunks_exact(3).map(|rgb| Rgba::<u8>::from([rgb[0], rgb[1], rgb[2], 255])).collect();
+        Ok(SystemPalette { colors })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        SystemPalette::from_pal_bytes(&fs::read(path)?)
+    }
+
+    pub fn has_emphasis(&self) -> bool {
+        self.colors.len() == NUM_SYSTEM_COLORS_WITH_EMPHASIS
+    }
+
+    // emphasis is the MASK_EMPHASIZE_* bits. Palettes without emphasis colors approximate it by
+    // darkening the channels that aren't emphasized. As on the NES, setting all three bits
+    // darkens every channel.
+    pub fn get_color_rgba(&self, color: u8, emphasis: u8) -> Rgba<u8> {
+        let emphasis = (emphasis & MASK_EMPHASIS) >> 5;
+        if self.has_emphasis() {
+            return self.colors[emphasis as usize * NUM_SYSTEM_COLORS + (color & 0x3f) as usize];
+        }
+
+        let mut rgba = self.colors[(color & 0x3f) as usize];
+        if emphasis != 0 {
+            let all_emphasized = emphasis == MASK_EMPHASIS >> 5;
+            for channel in 0..3 {
+                if all_emphasized || emphasis & (1 << channel) == 0 {
+                    rgba[channel] = (rgba[channel] as f32 * EMPHASIS_ATTENUATION) as u8;
+                }
+            }
         }
+        rgba
     }
 }
 
-const NTSC_PALETTE: &'static [u8; 64 * 3] = include_bytes!("ntscpalette.pal");
 
-fn get_color_rgba(color: u8) -> Rgba<u8> {
-    let rgb = NTSC_PALETTE.chunks_exact(3).nth((color & 0x3f) as usize).unwrap();
-    Rgba::<u8>::from([rgb[0], rgb[1], rgb[2], 255])
+// Palette RAM repeats every 0x20 bytes from 0x3F00 to 0x3FFF. Within it, entries 0x10/0x14/0x18/0x1C
+// (color 0 of each sprite palette) are mirrors of 0x00/0x04/0x08/0x0C.
+fn palette_ram_index(address: u16) -> usize {
+    let index = address as usize & (PALETTE_SIZE_IN_BYTES - 1);
+    if index & 0x13 == 0x10 {
+        index & !0x10
+    } else {
+        index
+    }
 }
 
+// A pattern unpacked to one pixel value per byte, row by row, for drawing
+type DecodedPattern = [[u8; TILE_WIDTH_IN_PIXELS]; TILE_HEIGHT_IN_PIXELS];
+
+fn decode_pattern(pattern: &[u8; PATTERN_SIZE_IN_BYTES]) -> DecodedPattern {
+    let mut decoded = [[0u8; TILE_WIDTH_IN_PIXELS]; TILE_HEIGHT_IN_PIXELS];
+    for (y, row) in decoded.iter_mut().enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = get_pixel_from_pattern(pattern, x, y);
+        }
+    }
+    decoded
+}
 
 pub fn get_pixel_from_pattern(pattern: &[u8; PATTERN_SIZE_IN_BYTES], x: usize, y: usize) -> u8 {
     (pattern[y * 2 + (x / 4)] >> (2 * (3 - (x % 4)))) & 0x3
@@ -105,76 +555,417 @@ pub fn set_pixel_in_pattern(pattern: &mut [u8; PATTERN_SIZE_IN_BYTES], x: usize,
     *b |= pixel << (2 * (3 - (x % 4)));
 }
 
+pub fn pattern_from_chr(chr: &[u8; CHR_PATTERN_SIZE_IN_BYTES]) -> [u8; PATTERN_SIZE_IN_BYTES] {
+    let mut pattern = [0u8; PATTERN_SIZE_IN_BYTES];
+    for y in 0..TILE_HEIGHT_IN_PIXELS {
+        for x in 0..TILE_WIDTH_IN_PIXELS {
+            let bit0 = (chr[y] >> (7 - x)) & 1;
+            let bit1 = (chr[y + TILE_HEIGHT_IN_PIXELS] >> (7 - x)) & 1;
+            set_pixel_in_pattern(&mut pattern, x, y, bit1 << 1 | bit0);
+        }
+    }
+    pattern
+}
+
+pub fn pattern_to_chr(pattern: &[u8; PATTERN_SIZE_IN_BYTES]) -> [u8; CHR_PATTERN_SIZE_IN_BYTES] {
+    let mut chr = [0u8; CHR_PATTERN_SIZE_IN_BYTES];
+    for y in 0..TILE_HEIGHT_IN_PIXELS {
+        for x in 0..TILE_WIDTH_IN_PIXELS {
+            let pixel = get_pixel_from_pattern(pattern, x, y);
+            chr[y] |= (pixel & 1) << (7 - x);
+            chr[y + TILE_HEIGHT_IN_PIXELS] |= (pixel >> 1) << (7 - x);
+        }
+    }
+    chr
+}
+
 impl Ppu {
-    pub fn draw_image(&self, image: &mut RgbaImage) {
-        for (dy, line_chunk) in (0..DISPLAY_HEIGHT).zip(image.chunks_mut(4 * DISPLAY_WIDTH)) {
-            let world_y = dy + self.scroll_y as usize;
-            let tile_y = world_y / 8;
-            let subtile_y = world_y % 8;
-
-            let mut sprites_on_line: [Option<&Sprite>; MAX_SPRITES_ON_LINE] =
-                [None; MAX_SPRITES_ON_LINE];
-            let mut num_sprites_on_line = 0;
-            for i in 0..NUM_SPRITES {
-                let sprite = &self.sprites[i];
-                // TODO: support 8x16 sprites
-                if dy >= sprite.y as usize && dy < sprite.y as usize + TILE_HEIGHT_IN_PIXELS {
-                    sprites_on_line[num_sprites_on_line] = Some(&sprite);
-                    num_sprites_on_line += 1;
-                    if num_sprites_on_line >= MAX_SPRITES_ON_LINE {
-                        break;
-                    }
+    // The image must be the size of the visible area. Lines outside the visible area are still
+    // drawn internally, so sprite 0 hits there are reported.
+    pub fn draw_image(&self, image: &mut RgbaImage) -> PpuStatus {
+        let area = self.visible_area();
+        assert_eq!(image.dimensions(), (area.width as u32, area.height as u32),
+                   "image must be the size of the visible area");
+        self.draw_pixels(image, 4 * area.width, PixelFormat::Rgba8888)
+    }
+
+    // Like draw_image, but into any buffer, in any PixelFormat. Each line of the visible area
+    // starts stride bytes after the previous one; bytes between the end of a line and the start
+    // of the next are left alone.
+    pub fn draw_pixels(&self, buffer: &mut [u8], stride: usize, format: PixelFormat) -> PpuStatus {
+        let area = self.visible_area();
+        let line_size = area.width * format.bytes_per_pixel();
+        assert!(stride >= line_size, "stride must be at least {} bytes", line_size);
+        assert!(area.height == 0 || buffer.len() >= stride * (area.height - 1) + line_size,
+                "buffer is too small for the visible area");
+
+        let frame = self.set_up_frame(format);
+        let mut status = PpuStatus {
+            sprite_overflow: frame.sprite_lines.overflow,
+            ..Default::default()
+        };
+
+        let num_threads = cmp::min(self.render_threads, DISPLAY_HEIGHT);
+        if num_threads <= 1 {
+            status.sprite_zero_hit_line = self.draw_lines(&frame, 0..DISPLAY_HEIGHT, buffer, stride);
+            return status;
+        }
+
+        // Each thread draws a band of lines into its own part of the buffer
+        let lines_per_thread = DISPLAY_HEIGHT.div_ceil(num_threads);
+        status.sprite_zero_hit_line = thread::scope(|scope| {
+            let mut rest = buffer;
+            let mut threads = Vec::new();
+            for top in (0..DISPLAY_HEIGHT).step_by(lines_per_thread) {
+                let bottom = cmp::min(top + lines_per_thread, DISPLAY_HEIGHT);
+                let num_image_lines = cmp::min(bottom, area.y + area.height).saturating_sub(cmp::max(top, area.y));
+                let band_size = cmp::min(num_image_lines * stride, rest.len());
+                let (band, remaining) = mem::take(&mut rest).split_at_mut(band_size);
+                rest = remaining;
+                let frame = &frame;
+                threads.push(scope.spawn(move || self.draw_lines(frame, top..bottom, band, stride)));
+            }
+            // The bands are in order, so the first band with a hit has the frame's first hit
+            threads.into_iter().filter_map(|thread| thread.join().unwrap()).next()
+        });
+        status
+    }
+
+    // Work out everything that stays the same for the whole frame
+    fn set_up_frame(&self, format: PixelFormat) -> FrameSetup {
+        // Every color in the output format, worked out once for the whole frame. Greyscale keeps
+        // only the brightness bits of each color.
+        let color_mask = if self.mask & MASK_GREYSCALE != 0 { 0x30 } else { 0x3f };
+        let mut pixel_colors = [[0u8; 4]; NUM_SYSTEM_COLORS];
+        for (color, pixel) in pixel_colors.iter_mut().enumerate() {
+            let color = color as u8 & color_mask;
+            let rgba = self.system_palette.get_color_rgba(color, self.mask);
+            let (r, g, b, a) = (rgba[0], rgba[1], rgba[2], rgba[3]);
+            *pixel = match format {
+                PixelFormat::Indexed => [color, 0, 0, 0],
+                PixelFormat::Rgba8888 => [r, g, b, a],
+                PixelFormat::Bgra8888 => [b, g, r, a],
+                PixelFormat::Rgb565 => {
+                    let rgb565 = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                    [rgb565 as u8, (rgb565 >> 8) as u8, 0, 0]
+                },
+            };
+        }
+
+        FrameSetup {
+            area: self.visible_area(),
+            show_bg: self.mask & MASK_SHOW_BG != 0,
+            show_sprites: self.mask & MASK_SHOW_SPRITES != 0,
+            show_bg_left: self.mask & MASK_SHOW_BG_LEFT != 0,
+            show_sprites_left: self.mask & MASK_SHOW_SPRITES_LEFT != 0,
+            sprite_lines: self.evaluate_sprites(),
+            format,
+            pixel_colors,
+        }
+    }
+
+    // Draw a range of lines into buffer, which holds just the visible ones among them, stride bytes
+    // apart. Returns the first line in the range with a sprite 0 hit.
+    fn draw_lines(&self, frame: &FrameSetup, lines: Range<usize>, buffer: &mut [u8], stride: usize) -> Option<usize> {
+        let area = frame.area;
+        let bytes_per_pixel = frame.format.bytes_per_pixel();
+        let mut buffer_lines = buffer.chunks_mut(stride);
+        let mut line_pixels = [0u8; 4 * DISPLAY_WIDTH];
+        let mut sprite_zero_hit_line = None;
+
+        // Effects before the first line are applied along with that line's
+        let mut raster_state = RasterState {
+            scroll_x: self.scroll_x,
+            scroll_y: self.scroll_y,
+            palette: self.palette,
+            chr_banks: self.chr_banks,
+        };
+        let mut raster_effects = self.raster_effects.iter().peekable();
+
+        for dy in lines {
+            // Apply raster effects for this line
+            while let Some(&&(line, effect)) = raster_effects.peek() {
+                if line > dy {
+                    break;
+                }
+                raster_state.apply(effect);
+                raster_effects.next();
+            }
+
+            let mut line = Line::default();
+            if frame.show_bg {
+                self.draw_bg_line(&raster_state, dy, &mut line);
+                if !frame.show_bg_left {
+                    line.clip_bg_left();
+                }
+            }
+            if frame.show_sprites {
+                self.draw_sprite_line(&raster_state, dy, frame.sprite_lines.on_line(dy), &mut line);
+                if !frame.show_sprites_left {
+                    line.clip_sprites_left();
                 }
             }
 
-            // Make sprite variables immutable
-            let sprites_on_line = sprites_on_line;
-            let num_sprites_on_line = num_sprites_on_line;
-
-            for (dx, rgba_chunk) in (0..DISPLAY_WIDTH).zip(line_chunk.chunks_exact_mut(4)) {
-                let world_x = dx + self.scroll_x as usize;
-                let tile_x = world_x / 8;
-                let subtile_x = world_x % 8;
-
-                let mut palette_index = 0;
-                let mut sprite_drawn = false;
-
-                for i in 0..num_sprites_on_line {
-                    let sprite = sprites_on_line[i].unwrap();
-                    if dx >= sprite.x as usize && dx < sprite.x as usize + TILE_WIDTH_IN_PIXELS {
-                        let sprite_col = dx - sprite.x as usize;
-                        let sprite_row = dy - sprite.y as usize;
-                        let sprite_pattern = self.get_pattern(sprite.tile);
-                        let pixel = get_pixel_from_pattern(&sprite_pattern, sprite_col, sprite_row);
-                        if pixel != 0 { // Color 0 is transparent
-                            // Draw sprite
-                            palette_index = self.get_sprite_color(pixel, sprite.attrib);
-                            sprite_drawn = true;
-                            break;
-                        }
-                    }
+            // Sprite 0 hits regardless of priority, and even if other sprites cover it.
+            // It can't hit where either layer is hidden, and as on the NES, it never hits at x=255.
+            if sprite_zero_hit_line.is_none() &&
+               (0..DISPLAY_WIDTH - 1).any(|dx| line.sprite_zero[dx] && line.bg_opaque(dx)) {
+                sprite_zero_hit_line = Some(dy);
+            }
+
+            let mut line_colors = [0u8; DISPLAY_WIDTH];
+            for (dx, color) in line_colors.iter_mut().enumerate() {
+                *color = raster_state.palette[line.palette_address(dx) as usize];
+            }
+            frame.encode_line(&line_colors, &mut line_pixels);
+
+            if dy >= area.y && dy < area.y + area.height {
+                let visible = &line_pixels[bytes_per_pixel * area.x .. bytes_per_pixel * (area.x + area.width)];
+                buffer_lines.next().unwrap()[..visible.len()].copy_from_slice(visible);
+            }
+        }
+
+        sprite_zero_hit_line
+    }
+
+    // Find the sprites on every line, once for the whole frame.
+    fn evaluate_sprites(&self) -> SpriteLines {
+        let (num_sprites, max_per_line) = match self.sprite_limits {
+            SpriteLimits::Extended => (NUM_SPRITES, MAX_SPRITES_ON_LINE),
+            SpriteLimits::Hardware => (HARDWARE_NUM_SPRITES, HARDWARE_MAX_SPRITES_ON_LINE),
+        };
+        let mut lines = SpriteLines {
+            indexes: vec![0; DISPLAY_HEIGHT * max_per_line],
+            counts: [0; DISPLAY_HEIGHT],
+            max_per_line,
+            overflow: false,
+        };
+
+        // As on the NES, sprites are still evaluated (and can overflow) while only the background
+        // is shown, but not while both layers are hidden.
+        if self.mask & (MASK_SHOW_BG | MASK_SHOW_SPRITES) == 0 {
+            return lines;
+        }
+        let num_sprites_in_use = self.sprites[..num_sprites].iter()
+            .rposition(|sprite| (sprite.y as usize) < DISPLAY_HEIGHT)
+            .map_or(0, |i| i + 1);
+
+        // With flicker on, evaluation starts at a different sprite each frame, so that a different
+        // sprite is dropped each frame on lines with too many sprites.
+        let first_sprite = if self.sprite_flicker && num_sprites_in_use != 0 {
+            self.flicker_offset % num_sprites_in_use
+        } else {
+            0
+        };
+
+        let sprite_height = self.sprite_height();
+        for n in 0..num_sprites_in_use {
+            let index = (first_sprite + n) % num_sprites_in_use;
+            let top = self.sprites[index].y as usize;
+            for y in top..cmp::min(top + sprite_height, DISPLAY_HEIGHT) {
+                let count = &mut lines.counts[y];
+                if *count < max_per_line {
+                    lines.indexes[y * max_per_line + *count] = index as u16;
+                    *count += 1;
+                } else {
+                    lines.overflow = true;
                 }
+            }
+        }
+        lines
+    }
 
-                if !sprite_drawn {
-                    // Draw background
-                    let tile = self.get_tile(tile_x, tile_y);
-                    let pattern = self.get_pattern(tile);
-                    let pixel = get_pixel_from_pattern(&pattern, subtile_x, subtile_y);
-                    let attrib = self.get_attribute(tile_x, tile_y);
-                    palette_index = self.get_bg_color(pixel, attrib);
+    // Draw a line of the background a tile at a time
+    fn draw_bg_line(&self, raster_state: &RasterState, dy: usize, line: &mut Line) {
+        let world_y = dy + raster_state.scroll_y as usize;
+        let tile_y = world_y / TILE_HEIGHT_IN_PIXELS;
+        let subtile_y = world_y % TILE_HEIGHT_IN_PIXELS;
+        let first_tile_x = raster_state.scroll_x as usize / TILE_WIDTH_IN_PIXELS;
+        let fine_x = raster_state.scroll_x as usize % TILE_WIDTH_IN_PIXELS;
+
+        // Unless the scroll is a whole number of tiles, the line starts and ends partway through a tile
+        for i in 0..DISPLAY_WIDTH_IN_TILES + 1 {
+            let (tile_x, left) = (first_tile_x + i, i * TILE_WIDTH_IN_PIXELS);
+            let pattern = self.get_decoded_pattern(&raster_state.chr_banks,
+                self.bg_pattern_table * PATTERNS_PER_TABLE + self.get_tile(tile_x, tile_y) as usize);
+            let palette_base = self.get_attribute(tile_x, tile_y) * 4;
+            for (subtile_x, &pixel) in pattern[subtile_y].iter().enumerate() {
+                let dx = (left + subtile_x).wrapping_sub(fine_x);
+                if dx < DISPLAY_WIDTH {
+                    line.bg[dx] = if pixel == 0 { 0 } else { palette_base + pixel };
                 }
+            }
+        }
+    }
 
-                let rgba = get_color_rgba(palette_index);
-                rgba_chunk.copy_from_slice(&rgba.channels());
+    // Draw a line's sprites, frontmost first
+    fn draw_sprite_line(&self, raster_state: &RasterState, dy: usize, sprites: &[u16], line: &mut Line) {
+        let sprite_height = self.sprite_height();
+        for &index in sprites {
+            let sprite = &self.sprites[index as usize];
+            let mut sprite_row = dy - sprite.y as usize;
+            if sprite.flip_vert {
+                sprite_row = sprite_height - 1 - sprite_row;
+            }
+            let pattern = self.get_decoded_pattern(&raster_state.chr_banks,
+                                                   self.get_sprite_pattern_index(sprite.tile, sprite_row));
+            let pattern_row = &pattern[sprite_row % TILE_HEIGHT_IN_PIXELS];
+            for sprite_col in 0..TILE_WIDTH_IN_PIXELS {
+                let dx = sprite.x as usize + sprite_col;
+                if dx >= DISPLAY_WIDTH {
+                    break;
+                }
+                let pixel = if sprite.flip_horiz {
+                    pattern_row[TILE_WIDTH_IN_PIXELS - 1 - sprite_col]
+                } else {
+                    pattern_row[sprite_col]
+                };
+                if pixel == 0 { // Color 0 is transparent
+                    continue;
+                }
+                if index == 0 {
+                    line.sprite_zero[dx] = true;
+                }
+                if line.sprites[dx] == 0 {
+                    line.sprites[dx] = 0x10 + sprite.attrib * 4 + pixel;
+                    line.sprites_behind_bg[dx] = sprite.behind_bg;
+                }
             }
         }
     }
 
+    // Change a register from the start of the given line until the end of the frame. Effects stay
+    // in place from frame to frame until they are cleared. Effects on the same line are applied
+    // in the order they were added.
+    pub fn add_raster_effect(&mut self, line: usize, effect: RasterEffect) {
+        if let RasterEffect::ChrBank(size, window, _) = effect {
+            check_chr_window(size, window);
+        }
+        let index = self.raster_effects.iter().position(|&(l, _)| l > line)
+            .unwrap_or(self.raster_effects.len());
+        self.raster_effects.insert(index, (line, effect));
+    }
+
+    pub fn clear_raster_effects(&mut self) {
+        self.raster_effects.clear();
+    }
+
+    // Call once per frame, after the frame's sprites have been set.
+    pub fn end_frame(&mut self) {
+        if self.sprite_flicker {
+            self.flicker_offset = self.flicker_offset.wrapping_add(1);
+        }
+    }
+
+    pub fn system_palette(&self) -> &SystemPalette {
+        &self.system_palette
+    }
+
+    pub fn set_system_palette(&mut self, system_palette: SystemPalette) {
+        self.system_palette = system_palette;
+    }
+
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
+
+    pub fn set_mask(&mut self, mask: u8) {
+        self.mask = mask;
+    }
+
+    // Greyscale uses only the grey column of the system palette (colors 0x00, 0x10, 0x20, 0x30)
+    pub fn set_greyscale(&mut self, greyscale: bool) {
+        self.set_mask_bits(MASK_GREYSCALE, greyscale);
+    }
+
+    fn set_mask_bits(&mut self, bits: u8, on: bool) {
+        if on {
+            self.mask |= bits;
+        } else {
+            self.mask &= !bits;
+        }
+    }
+
+    pub fn set_show_bg(&mut self, show: bool) {
+        self.set_mask_bits(MASK_SHOW_BG, show);
+    }
+
+    pub fn set_show_sprites(&mut self, show: bool) {
+        self.set_mask_bits(MASK_SHOW_SPRITES, show);
+    }
+
+    pub fn set_show_bg_left(&mut self, show: bool) {
+        self.set_mask_bits(MASK_SHOW_BG_LEFT, show);
+    }
+
+    pub fn set_show_sprites_left(&mut self, show: bool) {
+        self.set_mask_bits(MASK_SHOW_SPRITES_LEFT, show);
+    }
+
+    // emphasis is any combination of the MASK_EMPHASIZE_* bits
+    pub fn set_emphasis(&mut self, emphasis: u8) {
+        self.mask = (self.mask & !MASK_EMPHASIS) | (emphasis & MASK_EMPHASIS);
+    }
+
+    pub fn overscan(&self) -> Overscan {
+        self.overscan
+    }
+
+    pub fn set_overscan(&mut self, overscan: Overscan) {
+        let area = overscan.visible_area();
+        assert!(area.width > 0 && area.height > 0 &&
+                area.x + area.width <= DISPLAY_WIDTH && area.y + area.height <= DISPLAY_HEIGHT,
+                "visible area must be inside the display");
+        self.overscan = overscan;
+    }
+
+    pub fn visible_area(&self) -> DisplayRect {
+        self.overscan.visible_area()
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    pub fn scroll(&self) -> (u32, u32) {
+        (self.scroll_x, self.scroll_y)
+    }
+
+    // Set the position of the screen's top-left corner in the tilemap, in pixels. Scrolling wraps
+    // around at the edges of the tilemap.
+    pub fn set_scroll(&mut self, x: u32, y: u32) {
+        self.scroll_x = x;
+        self.scroll_y = y;
+    }
+
+    // Map any tile position to the position where the tile is stored, wrapping around the edges of
+    // the tilemap and applying mirroring.
+    fn mirror_tile_position(&self, tile_x: usize, tile_y: usize) -> (usize, usize) {
+        let tile_x = tile_x % TILEMAP_WIDTH_IN_TILES;
+        let tile_y = tile_y % TILEMAP_HEIGHT_IN_TILES;
+        let screen_x = tile_x / DISPLAY_WIDTH_IN_TILES;
+        let screen_y = tile_y / DISPLAY_HEIGHT_IN_TILES;
+        let page = match self.mirroring {
+            Mirroring::Horizontal => screen_y,
+            Mirroring::Vertical => screen_x,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => return (tile_x, tile_y),
+        };
+        (page * DISPLAY_WIDTH_IN_TILES + tile_x % DISPLAY_WIDTH_IN_TILES, tile_y % DISPLAY_HEIGHT_IN_TILES)
+    }
+
     fn get_tile(&self, tile_x: usize, tile_y: usize) -> u8 {
+        let (tile_x, tile_y) = self.mirror_tile_position(tile_x, tile_y);
         self.tilemap[tile_y * TILEMAP_WIDTH_IN_TILES + tile_x]
     }
 
     pub fn set_tile(&mut self, tile_x: usize, tile_y: usize, name: u8) {
+        let (tile_x, tile_y) = self.mirror_tile_position(tile_x, tile_y);
         self.tilemap[tile_y * TILEMAP_WIDTH_IN_TILES + tile_x] = name;
     }
 
@@ -182,23 +973,161 @@ impl Ppu {
         self.sprites = [Sprite::default(); NUM_SPRITES];
     }
 
+    // attrib is the palette number, optionally ORed with SPRITE_BEHIND_BG, SPRITE_FLIP_HORIZ
+    // and SPRITE_FLIP_VERT.
     pub fn set_sprite(&mut self, num: usize, x: i32, y: i32, tile: u8, attrib: u8) {
         let mut new_sprite = Sprite::default();
-        if x >= 0 && x < 256 && y >= 0 && y < 256 {
+        if (0..256).contains(&x) && (0..256).contains(&y) {
             new_sprite.x = x as u8;
             new_sprite.y = y as u8;
             new_sprite.tile = tile;
-            new_sprite.attrib = attrib;
+            new_sprite.attrib = attrib & SPRITE_PALETTE_MASK;
+            new_sprite.behind_bg = attrib & SPRITE_BEHIND_BG != 0;
+            new_sprite.flip_horiz = attrib & SPRITE_FLIP_HORIZ != 0;
+            new_sprite.flip_vert = attrib & SPRITE_FLIP_VERT != 0;
         }
         self.sprites[num] = new_sprite;
     }
 
+    pub fn sprite_limits(&self) -> SpriteLimits {
+        self.sprite_limits
+    }
+
+    pub fn set_sprite_limits(&mut self, limits: SpriteLimits) {
+        self.sprite_limits = limits;
+    }
+
+    pub fn sprite_flicker(&self) -> bool {
+        self.sprite_flicker
+    }
+
+    // Rotate the order sprites are evaluated in each frame, so that sprites over the line limit
+    // flicker instead of disappearing. Sprites also trade places front-to-back as they rotate.
+    pub fn set_sprite_flicker(&mut self, flicker: bool) {
+        self.sprite_flicker = flicker;
+    }
+
+    pub fn render_threads(&self) -> usize {
+        self.render_threads
+    }
+
+    // Split draw_image's lines between this many threads. The image is the same however many
+    // threads draw it. 1, the default, draws on the calling thread.
+    pub fn set_render_threads(&mut self, num_threads: usize) {
+        assert!(num_threads >= 1, "there must be at least one render thread");
+        self.render_threads = num_threads;
+    }
+
+    pub fn set_sprite_size(&mut self, size: SpriteSize) {
+        self.sprite_size = size;
+    }
+
+    // Select pattern table 0 (patterns 0..255) or 1 (patterns 256..511) for the background
+    pub fn set_bg_pattern_table(&mut self, table: usize) {
+        assert!(table < 2, "pattern table must be 0 or 1");
+        self.bg_pattern_table = table;
+    }
+
+    // Select pattern table 0 or 1 for 8x8 sprites
+    pub fn set_sprite_pattern_table(&mut self, table: usize) {
+        assert!(table < 2, "pattern table must be 0 or 1");
+        self.sprite_pattern_table = table;
+    }
+
+    fn sprite_height(&self) -> usize {
+        match self.sprite_size {
+            SpriteSize::Size8x8 => TILE_HEIGHT_IN_PIXELS,
+            SpriteSize::Size8x16 => TILE_HEIGHT_IN_PIXELS * 2,
+        }
+    }
+
+    // Get the pattern used for a given row (after flipping) of a sprite
+    // 8x8 sprites use the sprite pattern table. 8x16 sprites choose their table with bit 0 of
+    // the tile number, as on the NES.
+    fn get_sprite_pattern_index(&self, tile: u8, sprite_row: usize) -> usize {
+        match self.sprite_size {
+            SpriteSize::Size8x8 => self.sprite_pattern_table * PATTERNS_PER_TABLE + tile as usize,
+            SpriteSize::Size8x16 => {
+                let table = (tile & 1) as usize;
+                let top = (tile & 0xFE) as usize;
+                table * PATTERNS_PER_TABLE + top + sprite_row / TILE_HEIGHT_IN_PIXELS
+            },
+        }
+    }
+
+    // Writes to whichever CHR bank is in the pattern's window
     pub fn set_pattern(&mut self, tile: usize, pattern: [u8; PATTERN_SIZE_IN_BYTES]) {
-        let addr = tile as usize * PATTERN_SIZE_IN_BYTES;
-        self.pattern_table[addr .. addr + PATTERN_SIZE_IN_BYTES].copy_from_slice(&pattern);
+        let addr = self.chr_memory_address(&self.chr_banks, tile);
+        self.store_pattern(addr, pattern);
+    }
+
+    fn store_pattern(&mut self, addr: usize, pattern: [u8; PATTERN_SIZE_IN_BYTES]) {
+        self.chr_memory[addr .. addr + PATTERN_SIZE_IN_BYTES].copy_from_slice(&pattern);
+        self.decoded_chr[addr / PATTERN_SIZE_IN_BYTES] = decode_pattern(&pattern);
+    }
+
+    // The number of 1 KiB banks of CHR memory
+    pub fn num_chr_banks(&self) -> usize {
+        self.chr_memory.len() / PATTERN_BANK_SIZE_IN_BYTES
+    }
+
+    // Grow or shrink CHR memory to num_banks 1 KiB banks, at least enough for both pattern tables.
+    // New banks are blank. Every window goes back to showing its default bank.
+    pub fn set_num_chr_banks(&mut self, num_banks: usize) {
+        assert!(num_banks >= NUM_CHR_WINDOWS, "CHR memory must have at least {} banks", NUM_CHR_WINDOWS);
+        self.chr_memory.resize(num_banks * PATTERN_BANK_SIZE_IN_BYTES, 0);
+        self.decoded_chr.resize(num_banks * PATTERNS_PER_CHR_BANK, [[0; TILE_WIDTH_IN_PIXELS]; TILE_HEIGHT_IN_PIXELS]);
+        self.chr_banks = DEFAULT_CHR_BANKS;
+    }
+
+    // Replace all of CHR memory with CHR data, like the CHR ROM of a cartridge. It must be a whole
+    // number of 1 KiB banks, and at least 8 KiB.
+    pub fn load_chr_banks(&mut self, chr: &[u8]) -> io::Result<()> {
+        if !chr.len().is_multiple_of(CHR_BANK_SIZE_IN_BYTES) || chr.len() < CHR_SIZE_IN_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("CHR banks must be a multiple of {} bytes and at least {} bytes long, not {}",
+                        CHR_BANK_SIZE_IN_BYTES, CHR_SIZE_IN_BYTES, chr.len())));
+        }
+        self.set_num_chr_banks(chr.len() / CHR_BANK_SIZE_IN_BYTES);
+        for (index, chunk) in chr.chunks_exact(CHR_PATTERN_SIZE_IN_BYTES).enumerate() {
+            let mut chr_pattern = [0u8; CHR_PATTERN_SIZE_IN_BYTES];
+            chr_pattern.copy_from_slice(chunk);
+            self.store_pattern(index * PATTERN_SIZE_IN_BYTES, pattern_from_chr(&chr_pattern));
+        }
+        Ok(())
+    }
+
+    // Show a bank of CHR memory in a window of the pattern tables, from now on. window and bank
+    // are counted in units of size: with ChrBankSize::Size4K, window 1 is the second pattern table
+    // and bank 2 is the third 4 KiB of CHR memory. As with a mapper that has less CHR ROM than its
+    // bank registers can address, bank numbers past the end of CHR memory wrap around.
+    pub fn set_chr_bank(&mut self, size: ChrBankSize, window: usize, bank: usize) {
+        map_chr_bank(&mut self.chr_banks, size, window, bank);
+    }
+
+    // Replace the patterns with CHR data: 8 KiB for both pattern tables, or 4 KiB for just the
+    // first one. They go into the banks the pattern tables are showing.
+    pub fn load_chr(&mut self, chr: &[u8]) -> io::Result<()> {
+        if chr.len() != CHR_SIZE_IN_BYTES && chr.len() != CHR_SIZE_IN_BYTES / 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("CHR data must be {} or {} bytes long, not {}",
+                        CHR_SIZE_IN_BYTES / 2, CHR_SIZE_IN_BYTES, chr.len())));
+        }
+        for (index, chunk) in chr.chunks_exact(CHR_PATTERN_SIZE_IN_BYTES).enumerate() {
+            let mut chr_pattern = [0u8; CHR_PATTERN_SIZE_IN_BYTES];
+            chr_pattern.copy_from_slice(chunk);
+            self.set_pattern(index, pattern_from_chr(&chr_pattern));
+        }
+        Ok(())
+    }
+
+    // Both pattern tables as 8 KiB of CHR data
+    pub fn chr(&self) -> Vec<u8> {
+        (0..2 * PATTERNS_PER_TABLE).flat_map(|index| pattern_to_chr(&self.get_pattern(index)).to_vec()).collect()
     }
 
     fn get_attribute(&self, tile_x: usize, tile_y: usize) -> u8 {
+        let (tile_x, tile_y) = self.mirror_tile_position(tile_x, tile_y);
         let attr_x = tile_x / 2;
         let attr_y = tile_y / 2;
         let sub_x = tile_x % 2;
@@ -209,6 +1138,7 @@ impl Ppu {
     }
 
     pub fn set_attribute(&mut self, tile_x: usize, tile_y: usize, attrib: u8) {
+        let (tile_x, tile_y) = self.mirror_tile_position(tile_x, tile_y);
         let attr_x = tile_x / 2;
         let attr_y = tile_y / 2;
         let sub_x = tile_x % 2;
@@ -219,43 +1149,58 @@ impl Ppu {
         *b |= attrib << (2 * (3 - index));
     }
 
-    fn get_pattern(&self, name: u8) -> [u8; PATTERN_SIZE_IN_BYTES] {
+    fn chr_memory_address(&self, chr_banks: &ChrBanks, index: usize) -> usize {
+        let bank = chr_banks[index / PATTERNS_PER_CHR_BANK] % self.num_chr_banks();
+        bank * PATTERN_BANK_SIZE_IN_BYTES + (index % PATTERNS_PER_CHR_BANK) * PATTERN_SIZE_IN_BYTES
+    }
+
+    fn get_decoded_pattern(&self, chr_banks: &ChrBanks, index: usize) -> &DecodedPattern {
+        &self.decoded_chr[self.chr_memory_address(chr_banks, index) / PATTERN_SIZE_IN_BYTES]
+    }
+
+    fn get_banked_pattern(&self, chr_banks: &ChrBanks, index: usize) -> [u8; PATTERN_SIZE_IN_BYTES] {
         let mut result = [0u8; PATTERN_SIZE_IN_BYTES];
-        let addr = name as usize * PATTERN_SIZE_IN_BYTES;
-        result.copy_from_slice(&self.pattern_table[addr .. addr + PATTERN_SIZE_IN_BYTES]);
+        let addr = self.chr_memory_address(chr_banks, index);
+        result.copy_from_slice(&self.chr_memory[addr .. addr + PATTERN_SIZE_IN_BYTES]);
         result
     }
 
-    // Get color of tilemap background
-    fn get_bg_color(&self, pixel: u8, attrib: u8) -> u8 {
-        if pixel == 0 {
-            self.palette[0]
-        } else {
-            self.palette[attrib as usize * 4 + (pixel & 0x3) as usize]
-        }
+    // index is 0..511. Patterns 256 and up are in the second pattern table. The pattern comes
+    // from whichever CHR bank is in its window.
+    pub fn get_pattern(&self, index: usize) -> [u8; PATTERN_SIZE_IN_BYTES] {
+        self.get_banked_pattern(&self.chr_banks, index)
+    }
+
+    // Read a byte of palette RAM. address is 0x3F00..0x3FFF, or just the low bits of it.
+    pub fn read_palette(&self, address: u16) -> u8 {
+        self.palette[palette_ram_index(address)]
     }
 
-    fn get_sprite_color(&self, pixel: u8, attrib: u8) -> u8 {
-        // Note that sprite color 0 is transparent. This function does not handle transparency.
-        self.palette[attrib as usize * 4 + 0x10 + (pixel & 0x3) as usize]
+    // Write a byte of palette RAM, with the same mirroring as the NES. As on the NES, only the
+    // low 6 bits are kept.
+    pub fn write_palette(&mut self, address: u16, color: u8) {
+        self.palette[palette_ram_index(address)] = color & 0x3f;
     }
 
-    // Set universal background color
+    // Set universal background color (0x00, and its mirror 0x10)
     pub fn set_common_bg_color(&mut self, color: u8) {
-        self.palette[0] = color;
+        self.write_palette(0x00, color);
     }
 
     // Set the three non-transparent colors of background palette 0..3
     pub fn set_bg_colors(&mut self, attrib: u8, colors: [u8; 3]) {
-        self.palette[attrib as usize * 4 + 1] = colors[0];
-        self.palette[attrib as usize * 4 + 2] = colors[1];
-        self.palette[attrib as usize * 4 + 3] = colors[2];
+        for (i, &color) in colors.iter().enumerate() {
+            self.write_palette((attrib & 0x3) as u16 * 4 + 1 + i as u16, color);
+        }
     }
 
     // Set the three non-transparent colors sprite palette 0..3
     pub fn set_sprite_colors(&mut self, attrib: u8, colors: [u8; 3]) {
-        self.palette[attrib as usize * 4 + 0x10 + 1] = colors[0];
-        self.palette[attrib as usize * 4 + 0x10 + 2] = colors[1];
-        self.palette[attrib as usize * 4 + 0x10 + 3] = colors[2];
+        for (i, &color) in colors.iter().enumerate() {
+            self.write_palette(0x10 + (attrib & 0x3) as u16 * 4 + 1 + i as u16, color);
+        }
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,110 @@
+// A debug view of the PPU's memory, for finding out where something on screen came from.
+//
+//   +---------------------------+----------------+
+//   |                           | pattern tables |
+//   |  whole 64x60 tilemap,     | 0 and 1        |
+//   |  with the visible         +----------------+
+//   |  area outlined            | palette RAM    |
+//   |                           |                |
+//   +---------------------------+----------------+
+//
+// Patterns are laid out 16 to a row, so pattern N of a table is at column N % 16, row N / 16.
+// The palette shows the 32 palette RAM entries as 16 swatches per row, background palettes on
+// the first row and sprite palettes on the second.
+
+use super::*;
+
+const TILEMAP_VIEW_WIDTH: usize = TILEMAP_WIDTH_IN_TILES * TILE_WIDTH_IN_PIXELS;
+const TILEMAP_VIEW_HEIGHT: usize = TILEMAP_HEIGHT_IN_TILES * TILE_HEIGHT_IN_PIXELS;
+const PATTERNS_PER_ROW: usize = 16;
+const PATTERN_TABLE_VIEW_WIDTH: usize = PATTERNS_PER_ROW * TILE_WIDTH_IN_PIXELS;
+const PATTERN_VIEW_HEIGHT: usize = PATTERNS_PER_TABLE / PATTERNS_PER_ROW * TILE_HEIGHT_IN_PIXELS;
+const PALETTE_SWATCH_SIZE: usize = 16;
+const PALETTE_VIEW_Y: usize = PATTERN_VIEW_HEIGHT + 16;
+
+pub const DEBUG_VIEW_WIDTH: usize = TILEMAP_VIEW_WIDTH + 2 * PATTERN_TABLE_VIEW_WIDTH;
+pub const DEBUG_VIEW_HEIGHT: usize = TILEMAP_VIEW_HEIGHT;
+
+const SCROLL_RECT_COLOR: [u8; 4] = [255, 0, 255, 255];
+const DEBUG_VIEW_BACKGROUND: [u8; 4] = [64, 64, 64, 255];
+
+impl Ppu {
+    // Colors are shown without greyscale or emphasis
+    fn debug_color(&self, color: u8) -> Rgba<u8> {
+        self.system_palette.get_color_rgba(color, 0)
+    }
+
+    // Draw the debug view. palette is the palette to show the pattern tables in: 0..3 for the
+    // background palettes, 4..7 for the sprite palettes.
+    pub fn draw_debug_view(&self, image: &mut RgbaImage, palette: u8) {
+        assert_eq!(image.dimensions(), (DEBUG_VIEW_WIDTH as u32, DEBUG_VIEW_HEIGHT as u32),
+                   "image must be DEBUG_VIEW_WIDTH x DEBUG_VIEW_HEIGHT");
+        for pixel in image.pixels_mut() {
+            *pixel = Rgba(DEBUG_VIEW_BACKGROUND);
+        }
+
+        // Tilemap, as the renderer sees it through mirroring
+        for tile_y in 0..TILEMAP_HEIGHT_IN_TILES {
+            for tile_x in 0..TILEMAP_WIDTH_IN_TILES {
+                let pattern = self.get_decoded_pattern(&self.chr_banks,
+                    self.bg_pattern_table * PATTERNS_PER_TABLE + self.get_tile(tile_x, tile_y) as usize);
+                let palette_base = self.get_attribute(tile_x, tile_y) as usize * 4;
+                let (left, top) = (tile_x * TILE_WIDTH_IN_PIXELS, tile_y * TILE_HEIGHT_IN_PIXELS);
+                for (y, row) in pattern.iter().enumerate() {
+                    for (x, &pixel) in row.iter().enumerate() {
+                        let color = self.palette[if pixel == 0 { 0 } else { palette_base + pixel as usize }];
+                        image.put_pixel((left + x) as u32, (top + y) as u32, self.debug_color(color));
+                    }
+                }
+            }
+        }
+
+        // Outline of the visible part of the tilemap, wrapping around the edges like scrolling does
+        let area = self.visible_area();
+        if area.width > 0 && area.height > 0 {
+            let (left, top) = (self.scroll_x as usize + area.x, self.scroll_y as usize + area.y);
+            for i in 0..area.width {
+                let x = (left + i) % TILEMAP_VIEW_WIDTH;
+                for &y in &[top, top + area.height - 1] {
+                    image.put_pixel(x as u32, (y % TILEMAP_VIEW_HEIGHT) as u32, Rgba(SCROLL_RECT_COLOR));
+                }
+            }
+            for i in 0..area.height {
+                let y = (top + i) % TILEMAP_VIEW_HEIGHT;
+                for &x in &[left, left + area.width - 1] {
+                    image.put_pixel((x % TILEMAP_VIEW_WIDTH) as u32, y as u32, Rgba(SCROLL_RECT_COLOR));
+                }
+            }
+        }
+
+        // Pattern tables, side by side
+        let palette_base = (palette as usize & 0x7) * 4;
+        for index in 0..2 * PATTERNS_PER_TABLE {
+            let pattern = self.get_pattern(index);
+            let table = index / PATTERNS_PER_TABLE;
+            let left = TILEMAP_VIEW_WIDTH + table * PATTERN_TABLE_VIEW_WIDTH +
+                       (index % PATTERNS_PER_ROW) * TILE_WIDTH_IN_PIXELS;
+            let top = (index % PATTERNS_PER_TABLE) / PATTERNS_PER_ROW * TILE_HEIGHT_IN_PIXELS;
+            for y in 0..TILE_HEIGHT_IN_PIXELS {
+                for x in 0..TILE_WIDTH_IN_PIXELS {
+                    // Color 0 is drawn as the common background color, as in draw_image
+                    let pixel = get_pixel_from_pattern(&pattern, x, y) as usize;
+                    let color = self.read_palette(if pixel == 0 { 0 } else { (palette_base + pixel) as u16 });
+                    image.put_pixel((left + x) as u32, (top + y) as u32, self.debug_color(color));
+                }
+            }
+        }
+
+        // Palette RAM, through mirroring
+        for entry in 0..PALETTE_SIZE_IN_BYTES {
+            let color = self.debug_color(self.read_palette(entry as u16));
+            let left = TILEMAP_VIEW_WIDTH + (entry % 16) * PALETTE_SWATCH_SIZE;
+            let top = PALETTE_VIEW_Y + (entry / 16) * PALETTE_SWATCH_SIZE;
+            for y in top..top + PALETTE_SWATCH_SIZE {
+                for x in left..left + PALETTE_SWATCH_SIZE {
+                    image.put_pixel(x as u32, y as u32, color);
+                }
+            }
+        }
+    }
+}
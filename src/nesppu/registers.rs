@@ -0,0 +1,255 @@
+// A register-level interface to the PPU, modelled on the NES's PPUADDR/PPUDATA and
+// OAMADDR/OAMDATA/OAM DMA. It reads and writes the same tilemap, patterns, palette and sprites
+// as the rest of the Ppu methods, laid out in the NES's address spaces:
+//
+//   VRAM 0x0000..0x1FFF  pattern tables, in CHR format
+//        0x2000..0x2FFF  nametables 0..3 (the four screens of the tilemap, with mirroring) and
+//                        their attribute tables
+//        0x3000..0x3EFF  mirror of 0x2000..0x2EFF
+//        0x3F00..0x3FFF  palette RAM
+//   OAM  0x00..0xFF      sprites 0..63, 4 bytes each: Y, tile, attributes, X
+//
+// Unlike on the NES, PPUADDR has no effect on scrolling, and the registers can be used at any
+// time, not just during vblank.
+
+use super::*;
+
+const VRAM_ADDRESS_MASK: u16 = 0x3FFF;
+const NAMETABLES_START: u16 = 0x2000;
+const PALETTE_START: u16 = 0x3F00;
+const NAMETABLE_SIZE_IN_BYTES: u16 = 0x400;
+const ATTRIBUTE_TABLE_OFFSET: u16 = 0x3C0;
+
+pub const OAM_SIZE_IN_BYTES: usize = HARDWARE_NUM_SPRITES * 4;
+
+// Control bits, laid out like the NES's PPUCTRL register. See Ppu::write_ppuctrl.
+pub const CTRL_NAMETABLE_X: u8 = 0x01;
+pub const CTRL_NAMETABLE_Y: u8 = 0x02;
+pub const CTRL_INCREMENT_DOWN: u8 = 0x04;
+pub const CTRL_SPRITE_TABLE: u8 = 0x08;
+pub const CTRL_BG_TABLE: u8 = 0x10;
+pub const CTRL_SPRITE_8X16: u8 = 0x20;
+
+// How far PPUADDR moves after each PPUDATA access (PPUCTRL bit 2 on the NES)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum VramIncrement {
+    Across, // 1 byte, the next tile along a row
+    Down,   // 32 bytes, the next tile down a column
+}
+
+impl Ppu {
+    // Read a byte of VRAM without going through PPUDATA
+    pub fn read_vram(&self, address: u16) -> u8 {
+        let address = address & VRAM_ADDRESS_MASK;
+        if address < NAMETABLES_START {
+            self.read_pattern_byte(address)
+        } else if address < PALETTE_START {
+            self.read_nametable_byte(address)
+        } else {
+            self.read_palette(address)
+        }
+    }
+
+    // Write a byte of VRAM without going through PPUDATA
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        let address = address & VRAM_ADDRESS_MASK;
+        if address < NAMETABLES_START {
+            self.write_pattern_byte(address, value);
+        } else if address < PALETTE_START {
+            self.write_nametable_byte(address, value);
+        } else {
+            self.write_palette(address, value);
+        }
+    }
+
+    // Patterns are in CHR format (see CHR_PATTERN_SIZE_IN_BYTES)
+    fn read_pattern_byte(&self, address: u16) -> u8 {
+        let index = address as usize / CHR_PATTERN_SIZE_IN_BYTES;
+        pattern_to_chr(&self.get_pattern(index))[address as usize % CHR_PATTERN_SIZE_IN_BYTES]
+    }
+
+    fn write_pattern_byte(&mut self, address: u16, value: u8) {
+        let index = address as usize / CHR_PATTERN_SIZE_IN_BYTES;
+        let mut chr = pattern_to_chr(&self.get_pattern(index));
+        chr[address as usize % CHR_PATTERN_SIZE_IN_BYTES] = value;
+        self.set_pattern(index, pattern_from_chr(&chr));
+    }
+
+    // Returns the top-left tile of the nametable's screen, and the offset into the nametable
+    fn nametable_position(address: u16) -> (usize, usize, u16) {
+        let nametable = ((address - NAMETABLES_START) / NAMETABLE_SIZE_IN_BYTES) % 4;
+        let offset = (address - NAMETABLES_START) % NAMETABLE_SIZE_IN_BYTES;
+        let screen_x = (nametable % 2) as usize * DISPLAY_WIDTH_IN_TILES;
+        let screen_y = (nametable / 2) as usize * DISPLAY_HEIGHT_IN_TILES;
+        (screen_x, screen_y, offset)
+    }
+
+    // Each attribute table byte covers 4x4 tiles. Its 2-bit fields are, from bit 0 up, the
+    // top-left, top-right, bottom-left and bottom-right 2x2 tiles. The bottom half of the last
+    // row of bytes is below the screen, so it isn't stored and reads as 0.
+    // set_attribute can give each tile its own palette, unlike the NES. Reads return the palette
+    // of the top-left tile of each 2x2.
+    fn read_nametable_byte(&self, address: u16) -> u8 {
+        let (screen_x, screen_y, offset) = Ppu::nametable_position(address);
+        if offset < ATTRIBUTE_TABLE_OFFSET {
+            let x = offset as usize % DISPLAY_WIDTH_IN_TILES;
+            let y = offset as usize / DISPLAY_WIDTH_IN_TILES;
+            return self.get_tile(screen_x + x, screen_y + y);
+        }
+
+        let offset = (offset - ATTRIBUTE_TABLE_OFFSET) as usize;
+        let mut value = 0;
+        for quadrant in 0..4 {
+            let x = (offset % 8) * 4 + (quadrant % 2) * 2;
+            let y = (offset / 8) * 4 + (quadrant / 2) * 2;
+            if y < DISPLAY_HEIGHT_IN_TILES {
+                value |= self.get_attribute(screen_x + x, screen_y + y) << (quadrant * 2);
+            }
+        }
+        value
+    }
+
+    fn write_nametable_byte(&mut self, address: u16, value: u8) {
+        let (screen_x, screen_y, offset) = Ppu::nametable_position(address);
+        if offset < ATTRIBUTE_TABLE_OFFSET {
+            let x = offset as usize % DISPLAY_WIDTH_IN_TILES;
+            let y = offset as usize / DISPLAY_WIDTH_IN_TILES;
+            self.set_tile(screen_x + x, screen_y + y, value);
+            return;
+        }
+
+        let offset = (offset - ATTRIBUTE_TABLE_OFFSET) as usize;
+        for quadrant in 0..4 {
+            let x = (offset % 8) * 4 + (quadrant % 2) * 2;
+            let y = (offset / 8) * 4 + (quadrant / 2) * 2;
+            if y < DISPLAY_HEIGHT_IN_TILES {
+                let attrib = (value >> (quadrant * 2)) & 0x3;
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                    self.set_attribute(screen_x + x + dx, screen_y + y + dy, attrib);
+                }
+            }
+        }
+    }
+
+    // PPUCTRL: sets the scroll's starting nametable, the VRAM increment, both pattern tables and
+    // the sprite size from the CTRL_* bits. Bits 6 and 7 (PPU master/slave and NMI enable) have
+    // nothing to control here and are ignored.
+    pub fn write_ppuctrl(&mut self, value: u8) {
+        let (scroll_x, scroll_y) = self.scroll();
+        let nametable_x = if value & CTRL_NAMETABLE_X != 0 { DISPLAY_WIDTH as u32 } else { 0 };
+        let nametable_y = if value & CTRL_NAMETABLE_Y != 0 { DISPLAY_HEIGHT as u32 } else { 0 };
+        self.set_scroll(scroll_x % DISPLAY_WIDTH as u32 + nametable_x, scroll_y % DISPLAY_HEIGHT as u32 + nametable_y);
+
+        self.set_vram_increment(if value & CTRL_INCREMENT_DOWN != 0 { VramIncrement::Down } else { VramIncrement::Across });
+        self.set_sprite_pattern_table(if value & CTRL_SPRITE_TABLE != 0 { 1 } else { 0 });
+        self.set_bg_pattern_table(if value & CTRL_BG_TABLE != 0 { 1 } else { 0 });
+        self.set_sprite_size(if value & CTRL_SPRITE_8X16 != 0 { SpriteSize::Size8x16 } else { SpriteSize::Size8x8 });
+    }
+
+    pub fn set_vram_increment(&mut self, increment: VramIncrement) {
+        self.vram_increment = increment;
+    }
+
+    // Resets PPUADDR's write toggle, so that the next write is the high byte. This is the side
+    // effect of reading PPUSTATUS on the NES.
+    pub fn reset_address_latch(&mut self) {
+        self.address_latch_low = false;
+    }
+
+    // PPUADDR: the first write sets the high byte of the VRAM address, the second the low byte.
+    pub fn write_ppuaddr(&mut self, value: u8) {
+        if self.address_latch_low {
+            self.vram_address = (self.vram_address & 0xFF00) | value as u16;
+        } else {
+            self.vram_address = ((value as u16) << 8 | (self.vram_address & 0x00FF)) & VRAM_ADDRESS_MASK;
+        }
+        self.address_latch_low = !self.address_latch_low;
+    }
+
+    fn increment_vram_address(&mut self) {
+        let step = match self.vram_increment {
+            VramIncrement::Across => 1,
+            VramIncrement::Down => 32,
+        };
+        self.vram_address = self.vram_address.wrapping_add(step) & VRAM_ADDRESS_MASK;
+    }
+
+    pub fn write_ppudata(&mut self, value: u8) {
+        let address = self.vram_address;
+        self.write_vram(address, value);
+        self.increment_vram_address();
+    }
+
+    // As on the NES, reads below the palette are delayed by one read: they return the contents of
+    // a buffer, then refill it from the current address. Palette reads are immediate, but still
+    // refill the buffer with the nametable byte "underneath" the palette.
+    pub fn read_ppudata(&mut self) -> u8 {
+        let address = self.vram_address;
+        let value = if address < PALETTE_START {
+            self.ppudata_read_buffer
+        } else {
+            self.read_palette(address)
+        };
+        self.ppudata_read_buffer = if address < PALETTE_START {
+            self.read_vram(address)
+        } else {
+            self.read_vram(address & !0x1000)
+        };
+        self.increment_vram_address();
+        value
+    }
+
+    pub fn write_oamaddr(&mut self, value: u8) {
+        self.oam_address = value;
+    }
+
+    // Sprite Y in OAM is one less than the sprite's top line. 0xFE and 0xFF both hide the sprite,
+    // and read back as 0xFE. Attribute bits 2..4 don't exist and read back as 0.
+    pub fn read_oam(&self, address: u8) -> u8 {
+        let sprite = &self.sprites[address as usize / 4];
+        match address % 4 {
+            0 => sprite.y.wrapping_sub(1),
+            1 => sprite.tile,
+            2 => {
+                sprite.attrib |
+                    if sprite.behind_bg { SPRITE_BEHIND_BG } else { 0 } |
+                    if sprite.flip_horiz { SPRITE_FLIP_HORIZ } else { 0 } |
+                    if sprite.flip_vert { SPRITE_FLIP_VERT } else { 0 }
+            },
+            _ => sprite.x,
+        }
+    }
+
+    pub fn write_oam(&mut self, address: u8, value: u8) {
+        let sprite = &mut self.sprites[address as usize / 4];
+        match address % 4 {
+            0 => sprite.y = value.saturating_add(1),
+            1 => sprite.tile = value,
+            2 => {
+                sprite.attrib = value & SPRITE_PALETTE_MASK;
+                sprite.behind_bg = value & SPRITE_BEHIND_BG != 0;
+                sprite.flip_horiz = value & SPRITE_FLIP_HORIZ != 0;
+                sprite.flip_vert = value & SPRITE_FLIP_VERT != 0;
+            },
+            _ => sprite.x = value,
+        }
+    }
+
+    pub fn read_oamdata(&self) -> u8 {
+        self.read_oam(self.oam_address)
+    }
+
+    pub fn write_oamdata(&mut self, value: u8) {
+        let address = self.oam_address;
+        self.write_oam(address, value);
+        self.oam_address = address.wrapping_add(1);
+    }
+
+    // Copy a 256-byte page into OAM through OAMDATA, starting at OAMADDR and wrapping around, like
+    // a write to the NES's OAMDMA register.
+    pub fn oam_dma(&mut self, page: &[u8; OAM_SIZE_IN_BYTES]) {
+        for &value in page.iter() {
+            self.write_oamdata(value);
+        }
+    }
+}
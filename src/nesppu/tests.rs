@@ -0,0 +1,1132 @@
+// Golden-image tests for the PPU.
+// Each test builds a scene, renders it with draw_image and compares the result against a
+// checked-in PNG in testdata/ppu. On a mismatch, the rendered image and a diff image (differing
+// pixels in red over a dimmed copy of the golden) are written to target/golden-diff.
+// Run the tests with UPDATE_GOLDENS=1 to rewrite the goldens after an intentional change.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use image::{Rgba, RgbaImage};
+use test::Bencher;
+
+use super::*;
+use super::registers::{OAM_SIZE_IN_BYTES, CTRL_NAMETABLE_X, CTRL_NAMETABLE_Y, CTRL_INCREMENT_DOWN,
+                       CTRL_SPRITE_TABLE, CTRL_BG_TABLE, CTRL_SPRITE_8X16};
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata").join("ppu").join(format!("{}.png", name))
+}
+
+fn diff_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("golden-diff")
+}
+
+fn make_diff_image(expected: &RgbaImage, actual: &RgbaImage) -> (RgbaImage, usize) {
+    let mut diff = RgbaImage::new(expected.width(), expected.height());
+    let mut num_different = 0;
+    for (x, y, pixel) in diff.enumerate_pixels_mut() {
+        let e = expected.get_pixel(x, y);
+        let a = actual.get_pixel(x, y);
+        if e != a {
+            *pixel = Rgba([255, 0, 0, 255]);
+            num_different += 1;
+        } else {
+            // Dim the matching pixels so the red ones stand out.
+            *pixel = Rgba([e[0] / 4, e[1] / 4, e[2] / 4, 255]);
+        }
+    }
+    (diff, num_different)
+}
+
+fn check_golden(name: &str, actual: &RgbaImage) {
+    let path = golden_path(name);
+
+    if env::var_os("UPDATE_GOLDENS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        actual.save(&path).unwrap();
+        return;
+    }
+
+    let expected = match image::open(&path) {
+        Ok(image) => image.into_rgba8(),
+        Err(e) => panic!("Could not load golden {}: {} (run with UPDATE_GOLDENS=1 to create it)",
+                         path.display(), e),
+    };
+
+    if expected.dimensions() != actual.dimensions() {
+        panic!("{}: golden is {:?} but rendered image is {:?}",
+               name, expected.dimensions(), actual.dimensions());
+    }
+
+    let (diff, num_different) = make_diff_image(&expected, actual);
+    if num_different != 0 {
+        let dir = diff_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let actual_path = dir.join(format!("{}.actual.png", name));
+        let diff_path = dir.join(format!("{}.diff.png", name));
+        actual.save(&actual_path).unwrap();
+        diff.save(&diff_path).unwrap();
+        panic!("{}: {} pixels differ from the golden; see {} and {}",
+               name, num_different, actual_path.display(), diff_path.display());
+    }
+}
+
+fn render(ppu: &Ppu) -> RgbaImage {
+    let area = ppu.visible_area();
+    let mut image = RgbaImage::new(area.width as u32, area.height as u32);
+    ppu.draw_image(&mut image);
+    image
+}
+
+// Builds a pattern from a function giving the pixel value at (x, y).
+fn make_pattern<F: Fn(usize, usize) -> u8>(f: F) -> [u8; PATTERN_SIZE_IN_BYTES] {
+    let mut pattern = [0u8; PATTERN_SIZE_IN_BYTES];
+    for y in 0..TILE_HEIGHT_IN_PIXELS {
+        for x in 0..TILE_WIDTH_IN_PIXELS {
+            set_pixel_in_pattern(&mut pattern, x, y, f(x, y));
+        }
+    }
+    pattern
+}
+
+const STRIPES_PATTERN_NAME: u8 = 1;
+const CHECKER_PATTERN_NAME: u8 = 2;
+const RING_PATTERN_NAME: u8 = 3;
+const ARROW_PATTERN_NAME: u8 = 4;
+
+// A PPU with a few test patterns and palettes loaded, and an empty tilemap.
+fn make_test_ppu() -> Ppu {
+    let mut ppu = Ppu::default();
+
+    ppu.set_pattern(STRIPES_PATTERN_NAME as usize, make_pattern(|x, _| (x / 2) as u8 % 4));
+    ppu.set_pattern(CHECKER_PATTERN_NAME as usize, make_pattern(|x, y| if (x / 2 + y / 2) % 2 == 0 { 3 } else { 0 }));
+    ppu.set_pattern(RING_PATTERN_NAME as usize, make_pattern(|x, y| {
+        let dx = x as i32 * 2 - 7;
+        let dy = y as i32 * 2 - 7;
+        match dx * dx + dy * dy {
+            0 ..= 16 => 1,
+            17  ..= 36 => 2,
+            37 ..= 64 => 3,
+            _ => 0,
+        }
+    }));
+    // An asymmetric shape, so that any mirroring or offset mistakes are visible.
+    ppu.set_pattern(ARROW_PATTERN_NAME as usize, make_pattern(|x, y| {
+        if y == 0 || x == 0 {
+            3
+        } else if x == y {
+            2
+        } else if x < y && y < 4 {
+            1
+        } else {
+            0
+        }
+    }));
+
+    ppu.set_common_bg_color(0x0F);
+    ppu.set_bg_colors(0, [0x16, 0x27, 0x30]);
+    ppu.set_bg_colors(1, [0x12, 0x21, 0x31]);
+    ppu.set_bg_colors(2, [0x19, 0x2A, 0x3A]);
+    ppu.set_bg_colors(3, [0x04, 0x14, 0x24]);
+    ppu.set_sprite_colors(0, [0x30, 0x10, 0x00]);
+    ppu.set_sprite_colors(1, [0x28, 0x18, 0x08]);
+    ppu.set_sprite_colors(2, [0x2C, 0x1C, 0x0C]);
+    ppu.set_sprite_colors(3, [0x25, 0x15, 0x05]);
+
+    ppu
+}
+
+// Fills the whole tilemap with a repeating arrangement of the test patterns and palettes.
+fn fill_background(ppu: &mut Ppu) {
+    for y in 0..TILEMAP_HEIGHT_IN_TILES {
+        for x in 0..TILEMAP_WIDTH_IN_TILES {
+            let name = ((x + y * 3) % 5) as u8;
+            ppu.set_tile(x, y, name);
+            ppu.set_attribute(x, y, ((x / 4 + y / 4) % 4) as u8);
+        }
+    }
+}
+
+#[test]
+fn background() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    check_golden("background", &render(&ppu));
+}
+
+#[test]
+fn background_scrolled() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_scroll(13, 21);
+    check_golden("background_scrolled", &render(&ppu));
+}
+
+#[test]
+fn sprites() {
+    let mut ppu = make_test_ppu();
+    for i in 0..16 {
+        let x = 16 + (i % 4) as i32 * 24;
+        let y = 16 + (i / 4) as i32 * 24;
+        ppu.set_sprite(i, x, y, RING_PATTERN_NAME + (i % 2) as u8, (i % 4) as u8);
+    }
+    // Partially off the right and bottom edges of the screen.
+    ppu.set_sprite(16, 252, 100, ARROW_PATTERN_NAME, 0);
+    ppu.set_sprite(17, 100, 236, ARROW_PATTERN_NAME, 1);
+    // Negative coordinates hide the sprite rather than wrapping around.
+    ppu.set_sprite(18, -4, 100, ARROW_PATTERN_NAME, 2);
+    check_golden("sprites", &render(&ppu));
+}
+
+#[test]
+fn sprites_overlapping() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    // Lower-numbered sprites are drawn in front, and transparent pixels show what is behind.
+    ppu.set_sprite(0, 100, 100, CHECKER_PATTERN_NAME, 0);
+    ppu.set_sprite(1, 104, 104, RING_PATTERN_NAME, 1);
+    ppu.set_sprite(2, 108, 108, ARROW_PATTERN_NAME, 2);
+    ppu.set_sprite(3, 102, 110, STRIPES_PATTERN_NAME, 3);
+    check_golden("sprites_overlapping", &render(&ppu));
+}
+
+#[test]
+fn sprites_flipped() {
+    let mut ppu = make_test_ppu();
+    let flips = [0, SPRITE_FLIP_HORIZ, SPRITE_FLIP_VERT, SPRITE_FLIP_HORIZ | SPRITE_FLIP_VERT];
+    for (i, &flip) in flips.iter().enumerate() {
+        let x = 32 + i as i32 * 16;
+        ppu.set_sprite(i, x, 32, ARROW_PATTERN_NAME, 1 | flip);
+    }
+    // Flipping must not bleed into the palette number.
+    ppu.set_sprite(4, 32, 64, ARROW_PATTERN_NAME, 3 | SPRITE_FLIP_HORIZ | SPRITE_FLIP_VERT);
+    check_golden("sprites_flipped", &render(&ppu));
+}
+
+// Returns whether any pixel in the given rectangle differs from the common background color.
+fn any_pixel_drawn(ppu: &Ppu, image: &RgbaImage, x: u32, y: u32, width: u32, height: u32) -> bool {
+    let bg = ppu.system_palette().get_color_rgba(ppu.palette[0], ppu.mask());
+    (y..y + height).any(|py| (x..x + width).any(|px| *image.get_pixel(px, py) != bg))
+}
+
+#[test]
+fn sprites_8x16() {
+    let mut ppu = make_test_ppu();
+    ppu.set_sprite_size(SpriteSize::Size8x16);
+    ppu.set_pattern(ARROW_PATTERN_NAME as usize + 1, make_pattern(|x, y| if x == y || x == 7 - y { 1 } else { 0 }));
+    ppu.set_pattern(PATTERNS_PER_TABLE + ARROW_PATTERN_NAME as usize, make_pattern(|x, y| ((x + y) % 4) as u8));
+    ppu.set_pattern(PATTERNS_PER_TABLE + ARROW_PATTERN_NAME as usize + 1, make_pattern(|_, y| (y % 4) as u8));
+
+    let flips = [0, SPRITE_FLIP_HORIZ, SPRITE_FLIP_VERT, SPRITE_FLIP_HORIZ | SPRITE_FLIP_VERT];
+    for (i, &flip) in flips.iter().enumerate() {
+        let x = 32 + i as i32 * 16;
+        // Even tile numbers use the first pattern table, odd ones the second.
+        ppu.set_sprite(i * 2, x, 32, ARROW_PATTERN_NAME, flip);
+        ppu.set_sprite(i * 2 + 1, x, 64, ARROW_PATTERN_NAME + 1, 2 | flip);
+    }
+    check_golden("sprites_8x16", &render(&ppu));
+}
+
+#[test]
+fn sprites_8x16_line_limit() {
+    let mut ppu = make_test_ppu();
+    ppu.set_sprite_size(SpriteSize::Size8x16);
+
+    // Fill every sprite slot on lines 100..115 with invisible sprites. Patterns 0x80 and 0x81 are blank.
+    for i in 0..MAX_SPRITES_ON_LINE {
+        ppu.set_sprite(i, 0, 100, 0x80, 0);
+    }
+    // This sprite is on the same 16 lines as the others, so it is dropped entirely...
+    ppu.set_sprite(MAX_SPRITES_ON_LINE, 50, 100, CHECKER_PATTERN_NAME, 0);
+    // ...and this one shares only its top half with them, so only its bottom half is drawn.
+    ppu.set_sprite(MAX_SPRITES_ON_LINE + 1, 80, 108, CHECKER_PATTERN_NAME, 0);
+
+    let image = render(&ppu);
+    assert!(!any_pixel_drawn(&ppu, &image, 50, 100, 8, 16));
+    assert!(!any_pixel_drawn(&ppu, &image, 80, 108, 8, 8));
+    assert!(any_pixel_drawn(&ppu, &image, 80, 116, 8, 8));
+}
+
+#[test]
+fn sprites_behind_background() {
+    let mut ppu = make_test_ppu();
+    for y in 0..DISPLAY_HEIGHT_IN_TILES {
+        for x in 0..DISPLAY_WIDTH_IN_TILES {
+            ppu.set_tile(x, y, CHECKER_PATTERN_NAME);
+        }
+    }
+
+    // In front of and behind the background
+    ppu.set_sprite(0, 40, 40, RING_PATTERN_NAME, 1);
+    ppu.set_sprite(1, 60, 40, RING_PATTERN_NAME, 1 | SPRITE_BEHIND_BG);
+
+    // A sprite behind the background still hides the sprites it is in front of, even where the
+    // background covers it.
+    ppu.set_sprite(2, 80, 40, CHECKER_PATTERN_NAME, 2 | SPRITE_BEHIND_BG);
+    ppu.set_sprite(3, 80, 40, RING_PATTERN_NAME, 3);
+
+    // ...but its transparent pixels do not.
+    ppu.set_sprite(4, 100, 40, ARROW_PATTERN_NAME, 2 | SPRITE_BEHIND_BG);
+    ppu.set_sprite(5, 100, 40, RING_PATTERN_NAME, 3);
+
+    check_golden("sprites_behind_background", &render(&ppu));
+}
+
+// Puts count sprites side by side on lines 100..107, starting with sprite number first.
+fn set_row_of_sprites(ppu: &mut Ppu, first: usize, count: usize) {
+    for i in 0..count {
+        ppu.set_sprite(first + i, i as i32 * 10, 100, CHECKER_PATTERN_NAME, 0);
+    }
+}
+
+fn sprite_drawn_at(ppu: &Ppu, image: &RgbaImage, x: u32) -> bool {
+    any_pixel_drawn(ppu, image, x, 100, 8, 8)
+}
+
+#[test]
+fn hardware_sprite_limits() {
+    let mut ppu = make_test_ppu();
+    ppu.set_sprite_limits(SpriteLimits::Hardware);
+    set_row_of_sprites(&mut ppu, 0, 9);
+    // Past the end of OAM on the original hardware
+    ppu.set_sprite(HARDWARE_NUM_SPRITES, 200, 50, CHECKER_PATTERN_NAME, 0);
+
+    let mut image = RgbaImage::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    let status = ppu.draw_image(&mut image);
+    assert!(status.sprite_overflow);
+    for i in 0..8 {
+        assert!(sprite_drawn_at(&ppu, &image, i * 10));
+    }
+    assert!(!sprite_drawn_at(&ppu, &image, 80));
+    assert!(!any_pixel_drawn(&ppu, &image, 200, 50, 8, 8));
+
+    ppu.set_sprite_limits(SpriteLimits::Extended);
+    let status = ppu.draw_image(&mut image);
+    assert!(!status.sprite_overflow);
+    assert!(sprite_drawn_at(&ppu, &image, 80));
+    assert!(any_pixel_drawn(&ppu, &image, 200, 50, 8, 8));
+}
+
+#[test]
+fn hardware_sprite_limits_exactly_full() {
+    let mut ppu = make_test_ppu();
+    ppu.set_sprite_limits(SpriteLimits::Hardware);
+    set_row_of_sprites(&mut ppu, 0, HARDWARE_MAX_SPRITES_ON_LINE);
+    let mut image = RgbaImage::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    assert!(!ppu.draw_image(&mut image).sprite_overflow);
+}
+
+#[test]
+fn sprite_flicker() {
+    let mut ppu = make_test_ppu();
+    ppu.set_sprite_limits(SpriteLimits::Hardware);
+    ppu.set_sprite_flicker(true);
+    set_row_of_sprites(&mut ppu, 0, 10);
+
+    // Over 10 frames, each sprite is dropped on exactly two of them.
+    let mut times_drawn = [0; 10];
+    let mut image = RgbaImage::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    for _ in 0..10 {
+        assert!(ppu.draw_image(&mut image).sprite_overflow);
+        for (i, times) in times_drawn.iter_mut().enumerate() {
+            if sprite_drawn_at(&ppu, &image, i as u32 * 10) {
+                *times += 1;
+            }
+        }
+        ppu.end_frame();
+    }
+    assert_eq!(times_drawn, [8; 10]);
+}
+
+fn sprite_zero_hit_line(ppu: &Ppu) -> Option<usize> {
+    let mut image = RgbaImage::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    let status = ppu.draw_image(&mut image);
+    assert_eq!(status.sprite_zero_hit(), status.sprite_zero_hit_line.is_some());
+    status.sprite_zero_hit_line
+}
+
+#[test]
+fn sprite_zero_hit() {
+    let mut ppu = make_test_ppu();
+    // One background tile, covering (80, 80) to (87, 87)
+    ppu.set_tile(10, 10, CHECKER_PATTERN_NAME);
+
+    // No background under sprite 0
+    ppu.set_sprite(0, 40, 40, RING_PATTERN_NAME, 0);
+    assert_eq!(sprite_zero_hit_line(&ppu), None);
+
+    // Only other sprites overlap the background
+    ppu.set_sprite(1, 80, 80, RING_PATTERN_NAME, 0);
+    assert_eq!(sprite_zero_hit_line(&ppu), None);
+
+    // Sprite 0 starts above the background tile, so the hit is on the tile's first line.
+    ppu.set_sprite(0, 80, 76, ARROW_PATTERN_NAME, 0);
+    assert_eq!(sprite_zero_hit_line(&ppu), Some(80));
+
+    // Covered by another sprite, and behind the background: still a hit
+    ppu.set_sprite(0, 80, 78, ARROW_PATTERN_NAME, SPRITE_BEHIND_BG);
+    ppu.set_sprite(1, 80, 78, CHECKER_PATTERN_NAME, 0);
+    assert_eq!(sprite_zero_hit_line(&ppu), Some(80));
+}
+
+#[test]
+fn sprite_zero_hit_not_at_right_edge() {
+    let mut ppu = make_test_ppu();
+    ppu.set_tile(DISPLAY_WIDTH_IN_TILES - 1, 10, STRIPES_PATTERN_NAME);
+    // Only the sprite's leftmost column is on screen, at x=255.
+    ppu.set_sprite(0, DISPLAY_WIDTH as i32 - 1, 80, CHECKER_PATTERN_NAME, 0);
+    assert_eq!(sprite_zero_hit_line(&ppu), None);
+    ppu.set_sprite(0, DISPLAY_WIDTH as i32 - 2, 80, CHECKER_PATTERN_NAME, 0);
+    assert_eq!(sprite_zero_hit_line(&ppu), Some(80));
+}
+
+#[test]
+fn raster_effects() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_scroll(4, 0);
+
+    ppu.add_raster_effect(40, RasterEffect::ScrollX(40));
+    // Applies to sprites and the background alike
+    ppu.add_raster_effect(80, RasterEffect::PaletteColor(0x00, 0x2D));
+    ppu.add_raster_effect(80, RasterEffect::PaletteColor(0x11, 0x16));
+    ppu.set_sprite(0, 60, 76, RING_PATTERN_NAME, 0);
+    // Added out of order
+    ppu.add_raster_effect(200, RasterEffect::ScrollY(100));
+    ppu.add_raster_effect(120, RasterEffect::ScrollY(8));
+    // Fixed status bar
+    ppu.add_raster_effect(224, RasterEffect::ScrollX(0));
+    ppu.add_raster_effect(224, RasterEffect::ScrollY(0));
+    check_golden("raster_effects", &render(&ppu));
+
+    // Effects stay until they're cleared.
+    ppu.clear_raster_effects();
+    let mut expected = make_test_ppu();
+    fill_background(&mut expected);
+    expected.set_scroll(4, 0);
+    expected.set_sprite(0, 60, 76, RING_PATTERN_NAME, 0);
+    assert!(render(&ppu) == render(&expected));
+}
+
+#[test]
+fn scroll_wraps_around() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_scroll((TILEMAP_WIDTH_IN_TILES * TILE_WIDTH_IN_PIXELS) as u32 - 100,
+                   (TILEMAP_HEIGHT_IN_TILES * TILE_HEIGHT_IN_PIXELS) as u32 - 60);
+    check_golden("scroll_wraps_around", &render(&ppu));
+
+    // Scrolling a whole tilemap further shows the same thing.
+    let mut wrapped = make_test_ppu();
+    fill_background(&mut wrapped);
+    wrapped.set_scroll((TILEMAP_WIDTH_IN_TILES * TILE_WIDTH_IN_PIXELS * 2) as u32 - 100,
+                       (TILEMAP_HEIGHT_IN_TILES * TILE_HEIGHT_IN_PIXELS * 3) as u32 - 60);
+    assert!(render(&ppu) == render(&wrapped));
+}
+
+#[test]
+fn mirroring() {
+    // Screen positions in tiles
+    let (left, right) = (0, DISPLAY_WIDTH_IN_TILES);
+    let (top, bottom) = (0, DISPLAY_HEIGHT_IN_TILES);
+
+    let cases = [
+        (Mirroring::Horizontal, [(left, top), (right, top)]),
+        (Mirroring::Horizontal, [(left, bottom), (right, bottom)]),
+        (Mirroring::Vertical, [(left, top), (left, bottom)]),
+        (Mirroring::Vertical, [(right, top), (right, bottom)]),
+        (Mirroring::SingleScreenLower, [(left, top), (right, bottom)]),
+        (Mirroring::SingleScreenUpper, [(right, top), (left, bottom)]),
+    ];
+    for &(mirroring, [(write_x, write_y), (read_x, read_y)]) in cases.iter() {
+        let mut ppu = make_test_ppu();
+        ppu.set_mirroring(mirroring);
+        ppu.set_tile(write_x + 3, write_y + 5, RING_PATTERN_NAME);
+        ppu.set_attribute(write_x + 3, write_y + 5, 2);
+        assert_eq!(ppu.get_tile(read_x + 3, read_y + 5), RING_PATTERN_NAME, "{:?}", mirroring);
+        assert_eq!(ppu.get_attribute(read_x + 3, read_y + 5), 2, "{:?}", mirroring);
+    }
+
+    // Only horizontal mirroring and four-screen keep the top and bottom screens separate.
+    for &mirroring in [Mirroring::Horizontal, Mirroring::FourScreen].iter() {
+        let mut ppu = make_test_ppu();
+        ppu.set_mirroring(mirroring);
+        ppu.set_tile(3, 5, RING_PATTERN_NAME);
+        assert_eq!(ppu.get_tile(3, bottom + 5), 0, "{:?}", mirroring);
+    }
+
+    // The same scene with a scroll that crosses every screen edge
+    let mut ppu = make_test_ppu();
+    ppu.set_mirroring(Mirroring::Vertical);
+    fill_background(&mut ppu);
+    ppu.set_scroll(200, 200);
+    check_golden("mirroring_vertical", &render(&ppu));
+}
+
+#[test]
+fn overscan() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_sprite(0, 0, 0, RING_PATTERN_NAME, 0);
+    ppu.set_sprite(1, 248, 232, RING_PATTERN_NAME, 1);
+    let full = render(&ppu);
+
+    ppu.set_overscan(Overscan::Ntsc);
+    let ntsc = render(&ppu);
+    assert_eq!(ntsc.dimensions(), (256, 224));
+    check_golden("overscan_ntsc", &ntsc);
+    assert!(*ntsc == *image::imageops::crop_imm(&full, 0, 8, 256, 224).to_image());
+
+    let rect = DisplayRect { x: 13, y: 21, width: 100, height: 50 };
+    ppu.set_overscan(Overscan::Custom(rect));
+    let custom = render(&ppu);
+    assert_eq!(custom.dimensions(), (100, 50));
+    assert!(*custom == *image::imageops::crop_imm(&full, 13, 21, 100, 50).to_image());
+}
+
+#[test]
+fn sprite_zero_hit_in_overscan() {
+    // Hidden lines are still drawn, and can still cause a hit.
+    let mut ppu = make_test_ppu();
+    ppu.set_overscan(Overscan::Ntsc);
+    ppu.set_tile(10, 0, STRIPES_PATTERN_NAME);
+    ppu.set_sprite(0, 80, 2, STRIPES_PATTERN_NAME, 0);
+    let mut image = RgbaImage::new(256, 224);
+    assert_eq!(ppu.draw_image(&mut image).sprite_zero_hit_line, Some(2));
+}
+
+#[test]
+#[should_panic(expected = "image must be the size of the visible area")]
+fn overscan_image_size_checked() {
+    let mut ppu = make_test_ppu();
+    ppu.set_overscan(Overscan::Ntsc);
+    let mut image = RgbaImage::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    ppu.draw_image(&mut image);
+}
+
+#[test]
+fn system_palette() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    let default_image = render(&ppu);
+
+    // The shipped 512-color palette matches the built-in one closely when emphasis is off
+    let pal_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("palettes").join("ntsc.pal");
+    let palette = SystemPalette::load(&pal_path).unwrap();
+    assert!(palette.has_emphasis());
+    ppu.set_system_palette(palette);
+    let image = render(&ppu);
+    for (a, b) in image.pixels().zip(default_image.pixels()) {
+        for c in 0..3 {
+            assert!((a[c] as i32 - b[c] as i32).abs() <= 8, "{:?} vs {:?}", a, b);
+        }
+    }
+
+    // Color n is drawn as (n, 255 - n, 2n)
+    let bytes: Vec<u8> = (0..64u8).flat_map(|n| vec![n, 255 - n, 2 * n]).collect();
+    ppu.set_system_palette(SystemPalette::from_pal_bytes(&bytes).unwrap());
+    ppu.set_common_bg_color(0x2a);
+    let image = render(&ppu);
+    assert_eq!(*image.get_pixel(0, 0), Rgba([0x2a, 255 - 0x2a, 2 * 0x2a, 255]));
+}
+
+#[test]
+fn system_palette_wrong_size() {
+    assert!(SystemPalette::from_pal_bytes(&[0; 64 * 3]).is_ok());
+    assert!(SystemPalette::from_pal_bytes(&[0; 512 * 3]).is_ok());
+    assert!(SystemPalette::from_pal_bytes(&[0; 63 * 3]).is_err());
+    assert!(SystemPalette::from_pal_bytes(&[]).is_err());
+}
+
+#[test]
+fn greyscale() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_sprite(0, 100, 100, RING_PATTERN_NAME, 1);
+    ppu.set_greyscale(true);
+    let image = render(&ppu);
+    let greys: Vec<_> = [0x00, 0x10, 0x20, 0x30].iter().map(|&c| ppu.system_palette().get_color_rgba(c, 0)).collect();
+    assert!(image.pixels().all(|p| greys.contains(p)));
+    check_golden("greyscale", &image);
+}
+
+#[test]
+fn emphasis() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    let pal_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("palettes").join("ntsc.pal");
+    ppu.set_system_palette(SystemPalette::load(&pal_path).unwrap());
+    ppu.set_emphasis(MASK_EMPHASIZE_RED | MASK_EMPHASIZE_GREEN);
+    check_golden("emphasis", &render(&ppu));
+
+    // Emphasis doesn't touch the greyscale bit
+    ppu.set_greyscale(true);
+    ppu.set_emphasis(0);
+    assert_eq!(ppu.mask(), MASK_SHOW_ALL | MASK_GREYSCALE);
+}
+
+#[test]
+fn emphasis_without_emphasis_colors() {
+    let bytes: Vec<u8> = (0..64).flat_map(|_| vec![200, 100, 50]).collect();
+    let palette = SystemPalette::from_pal_bytes(&bytes).unwrap();
+    assert_eq!(palette.get_color_rgba(0x21, 0), Rgba([200, 100, 50, 255]));
+    assert_eq!(palette.get_color_rgba(0x21, MASK_EMPHASIZE_RED), Rgba([200, 74, 37, 255]));
+    // All three bits darken the whole picture
+    assert_eq!(palette.get_color_rgba(0x21, MASK_EMPHASIS), Rgba([149, 74, 37, 255]));
+}
+
+#[test]
+fn layers_hidden() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_sprite(0, 100, 100, RING_PATTERN_NAME, 1);
+    let bg = ppu.system_palette().get_color_rgba(ppu.palette[0], 0);
+
+    ppu.set_show_bg(false);
+    let image = render(&ppu);
+    assert!(any_pixel_drawn(&ppu, &image, 100, 100, 8, 8));
+    assert!(!any_pixel_drawn(&ppu, &image, 0, 0, 100, 100));
+
+    ppu.set_show_bg(true);
+    ppu.set_show_sprites(false);
+    let mut no_sprites = make_test_ppu();
+    fill_background(&mut no_sprites);
+    assert!(render(&ppu) == render(&no_sprites));
+
+    ppu.set_show_bg(false);
+    let image = render(&ppu);
+    assert!(image.pixels().all(|p| *p == bg));
+}
+
+#[test]
+fn left_column_clipping() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_sprite(0, 4, 100, CHECKER_PATTERN_NAME, 1);
+    ppu.set_sprite(1, 4, 20, RING_PATTERN_NAME, 2);
+    ppu.set_show_bg_left(false);
+    check_golden("clip_bg_left", &render(&ppu));
+    ppu.set_show_bg_left(true);
+    ppu.set_show_sprites_left(false);
+    check_golden("clip_sprites_left", &render(&ppu));
+}
+
+#[test]
+fn sprite_zero_hit_clipped() {
+    // Sprite 0 overlaps the background only in the leftmost 8 pixels
+    let mut ppu = make_test_ppu();
+    ppu.set_tile(0, 10, STRIPES_PATTERN_NAME);
+    ppu.set_sprite(0, 0, 80, STRIPES_PATTERN_NAME, 0);
+    assert_eq!(sprite_zero_hit_line(&ppu), Some(80));
+    ppu.set_show_bg_left(false);
+    assert_eq!(sprite_zero_hit_line(&ppu), None);
+    ppu.set_show_bg_left(true);
+    ppu.set_show_sprites_left(false);
+    assert_eq!(sprite_zero_hit_line(&ppu), None);
+    ppu.set_show_sprites_left(true);
+    ppu.set_show_sprites(false);
+    assert_eq!(sprite_zero_hit_line(&ppu), None);
+}
+
+#[test]
+fn no_sprite_overflow_with_rendering_off() {
+    let mut ppu = make_test_ppu();
+    ppu.set_sprite_limits(SpriteLimits::Hardware);
+    set_row_of_sprites(&mut ppu, 0, 9);
+    let mut image = RgbaImage::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    ppu.set_show_sprites(false);
+    assert!(ppu.draw_image(&mut image).sprite_overflow);
+    ppu.set_show_bg(false);
+    assert!(!ppu.draw_image(&mut image).sprite_overflow);
+}
+
+#[test]
+fn palette_mirroring() {
+    let mut ppu = Ppu::default();
+    for &(address, mirror) in &[(0x3F10, 0x3F00), (0x3F14, 0x3F04), (0x3F18, 0x3F08), (0x3F1C, 0x3F0C)] {
+        ppu.write_palette(address, 0x11);
+        assert_eq!(ppu.read_palette(mirror), 0x11);
+        ppu.write_palette(mirror, 0x22);
+        assert_eq!(ppu.read_palette(address), 0x22);
+    }
+
+    // Other sprite colors are separate from the background colors
+    ppu.write_palette(0x3F01, 0x01);
+    ppu.write_palette(0x3F11, 0x02);
+    assert_eq!(ppu.read_palette(0x3F01), 0x01);
+    assert_eq!(ppu.read_palette(0x3F11), 0x02);
+
+    // Palette RAM repeats every 0x20 bytes, and only holds 6 bits
+    ppu.write_palette(0x3FE5, 0xC7);
+    assert_eq!(ppu.read_palette(0x3F05), 0x07);
+    assert_eq!(ppu.read_palette(0x05), 0x07);
+}
+
+#[test]
+fn palette_mirroring_backdrop() {
+    // Writing sprite palette 0's color 0 changes the backdrop
+    let mut ppu = make_test_ppu();
+    ppu.write_palette(0x3F10, 0x2A);
+    let image = render(&ppu);
+    assert_eq!(*image.get_pixel(0, 0), ppu.system_palette().get_color_rgba(0x2A, 0));
+
+    ppu.add_raster_effect(120, RasterEffect::PaletteColor(0x10, 0x16));
+    let image = render(&ppu);
+    assert_eq!(*image.get_pixel(0, 119), ppu.system_palette().get_color_rgba(0x2A, 0));
+    assert_eq!(*image.get_pixel(0, 120), ppu.system_palette().get_color_rgba(0x16, 0));
+}
+
+// Writes bytes through PPUDATA, starting at address
+fn write_vram_bytes(ppu: &mut Ppu, address: u16, bytes: &[u8]) {
+    ppu.reset_address_latch();
+    ppu.write_ppuaddr((address >> 8) as u8);
+    ppu.write_ppuaddr(address as u8);
+    for &b in bytes {
+        ppu.write_ppudata(b);
+    }
+}
+
+#[test]
+fn registers_match_helpers() {
+    // The same scene as the background and sprites tests, built only through the registers
+    let mut expected = make_test_ppu();
+    fill_background(&mut expected);
+    expected.set_sprite(0, 100, 100, RING_PATTERN_NAME, 1 | SPRITE_FLIP_VERT);
+    expected.set_sprite(1, 30, 200, ARROW_PATTERN_NAME, 2 | SPRITE_FLIP_HORIZ | SPRITE_BEHIND_BG);
+
+    let mut ppu = Ppu::default();
+    let patterns: Vec<u8> = (0..0x2000).map(|address| expected.read_vram(address)).collect();
+    write_vram_bytes(&mut ppu, 0x0000, &patterns);
+    let palette: Vec<u8> = (0..0x20).map(|i| expected.read_palette(i)).collect();
+    write_vram_bytes(&mut ppu, 0x3F00, &palette);
+
+    // Draw the nametable a column at a time to exercise the 32-byte increment
+    ppu.set_vram_increment(VramIncrement::Down);
+    for nametable in 0..4u16 {
+        let base = 0x2000 + nametable * 0x400;
+        for x in 0..32u16 {
+            let column: Vec<u8> = (0..30).map(|y| expected.read_vram(base + y * 32 + x)).collect();
+            write_vram_bytes(&mut ppu, base + x, &column);
+        }
+    }
+    ppu.set_vram_increment(VramIncrement::Across);
+    for nametable in 0..4u16 {
+        let base = 0x2000 + nametable * 0x400 + 0x3C0;
+        let attributes: Vec<u8> = (0..64).map(|i| expected.read_vram(base + i)).collect();
+        write_vram_bytes(&mut ppu, base, &attributes);
+    }
+
+    let mut page = [0xFFu8; OAM_SIZE_IN_BYTES];
+    for (i, b) in page.iter_mut().enumerate().take(8) {
+        *b = expected.read_oam(i as u8);
+    }
+    ppu.write_oamaddr(0);
+    ppu.oam_dma(&page);
+
+    assert!(render(&ppu) == render(&expected));
+}
+
+#[test]
+fn chr_format() {
+    // A pattern with pixel values 0, 1, 2, 3 in columns 0-1, 2-3, 4-5, 6-7
+    let mut ppu = Ppu::default();
+    ppu.set_pattern(5, make_pattern(|x, _| (x / 2) as u8));
+    for row in 0..8 {
+        assert_eq!(ppu.read_vram(5 * 16 + row), 0b00110011);
+        assert_eq!(ppu.read_vram(5 * 16 + 8 + row), 0b00001111);
+    }
+    ppu.write_vram(0x1000 + 16 + 3, 0x81);
+    assert_eq!(get_pixel_from_pattern(&ppu.get_pattern(257), 0, 3), 1);
+    assert_eq!(get_pixel_from_pattern(&ppu.get_pattern(257), 7, 3), 1);
+    assert_eq!(get_pixel_from_pattern(&ppu.get_pattern(257), 1, 3), 0);
+}
+
+#[test]
+fn nametable_attributes() {
+    let mut ppu = Ppu::default();
+    ppu.write_vram(0x2400 + 0x3C0 + 9, 0b11_10_01_00);
+    // Byte 9 covers tiles 4..7 of rows 4..7, in the second screen
+    assert_eq!(ppu.get_attribute(32 + 4, 4), 0);
+    assert_eq!(ppu.get_attribute(32 + 6, 4), 1);
+    assert_eq!(ppu.get_attribute(32 + 4, 6), 2);
+    assert_eq!(ppu.get_attribute(32 + 7, 7), 3);
+    assert_eq!(ppu.read_vram(0x27C9), 0b11_10_01_00);
+
+    // The last row only has a top half
+    ppu.write_vram(0x23F8, 0xFF);
+    assert_eq!(ppu.read_vram(0x23F8), 0x0F);
+    assert_eq!(ppu.get_attribute(0, 30), 0);
+
+    // 0x3000 mirrors 0x2000, and nametable mirroring applies
+    ppu.set_mirroring(Mirroring::Vertical);
+    ppu.write_vram(0x3805, 0x42);
+    assert_eq!(ppu.read_vram(0x2005), 0x42);
+    assert_eq!(ppu.get_tile(5, 0), 0x42);
+}
+
+#[test]
+fn ppudata_reads() {
+    let mut ppu = Ppu::default();
+    write_vram_bytes(&mut ppu, 0x2000, &[1, 2, 3]);
+    write_vram_bytes(&mut ppu, 0x2F00, &[0x55]);
+    write_vram_bytes(&mut ppu, 0x3F00, &[0x21]);
+
+    // Reads are delayed by one, except from the palette
+    write_vram_bytes(&mut ppu, 0x2000, &[]);
+    ppu.read_ppudata();
+    assert_eq!(ppu.read_ppudata(), 1);
+    assert_eq!(ppu.read_ppudata(), 2);
+    write_vram_bytes(&mut ppu, 0x3F00, &[]);
+    assert_eq!(ppu.read_ppudata(), 0x21);
+    write_vram_bytes(&mut ppu, 0x2001, &[]);
+    assert_eq!(ppu.read_ppudata(), 0x55);
+
+    // Pattern table 1 is read from itself, not from pattern table 0
+    write_vram_bytes(&mut ppu, 0x0000, &[0xAA]);
+    write_vram_bytes(&mut ppu, 0x1000, &[0x55]);
+    write_vram_bytes(&mut ppu, 0x1000, &[]);
+    ppu.read_ppudata();
+    write_vram_bytes(&mut ppu, 0x1001, &[]);
+    assert_eq!(ppu.read_ppudata(), 0x55);
+}
+
+#[test]
+fn oam_registers() {
+    let mut ppu = Ppu::default();
+    ppu.write_oamaddr(4);
+    for &b in &[49, 7, 0xE3, 20] {
+        ppu.write_oamdata(b);
+    }
+    ppu.write_oamaddr(4);
+    assert_eq!(ppu.read_oamdata(), 49);
+    assert_eq!(ppu.read_oam(6), 0xE3 & !0x1C);
+    let sprite = ppu.sprites[1];
+    assert_eq!((sprite.x, sprite.y, sprite.tile, sprite.attrib), (20, 50, 7, 3));
+    assert!(sprite.behind_bg && sprite.flip_horiz && sprite.flip_vert);
+
+    // DMA starts at OAMADDR and wraps around
+    let mut page = [0u8; OAM_SIZE_IN_BYTES];
+    page[0] = 0x10;
+    page[255] = 0x20;
+    ppu.write_oamaddr(1);
+    ppu.oam_dma(&page);
+    assert_eq!(ppu.read_oam(1), 0x10);
+    assert_eq!(ppu.read_oam(0), 0x20);
+    assert_eq!(ppu.read_oamdata(), 0x10);
+}
+
+#[test]
+fn chr_round_trip() {
+    let mut ppu = make_test_ppu();
+    ppu.set_pattern(300, make_pattern(|x, y| ((x * 3 + y) % 4) as u8));
+    let chr = ppu.chr();
+    assert_eq!(chr.len(), CHR_SIZE_IN_BYTES);
+
+    let mut loaded = Ppu::default();
+    loaded.load_chr(&chr).unwrap();
+    for index in 0..2 * PATTERNS_PER_TABLE {
+        assert_eq!(loaded.get_pattern(index), ppu.get_pattern(index));
+    }
+
+    // A 4 KiB file only replaces the first pattern table
+    loaded.load_chr(&vec![0xFF; CHR_SIZE_IN_BYTES / 2]).unwrap();
+    assert_eq!(loaded.get_pattern(255), make_pattern(|_, _| 3));
+    assert_eq!(loaded.get_pattern(300), ppu.get_pattern(300));
+
+    assert!(loaded.load_chr(&chr[..100]).is_err());
+}
+
+#[test]
+fn chr_planes() {
+    // The arrow pattern's top row and left column are color 3, its diagonal color 2
+    let mut chr = [0u8; CHR_PATTERN_SIZE_IN_BYTES];
+    chr[0] = 0xFF;
+    chr[8] = 0xFF;
+    for y in 1..8 {
+        chr[y] = 0x80;
+        chr[8 + y] = 0x80 | (0x80 >> y);
+    }
+    let pattern = pattern_from_chr(&chr);
+    assert_eq!(get_pixel_from_pattern(&pattern, 5, 0), 3);
+    assert_eq!(get_pixel_from_pattern(&pattern, 0, 5), 3);
+    assert_eq!(get_pixel_from_pattern(&pattern, 5, 5), 2);
+    assert_eq!(get_pixel_from_pattern(&pattern, 5, 4), 0);
+    assert_eq!(pattern_to_chr(&pattern), chr);
+}
+
+#[test]
+fn debug_view() {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_mirroring(Mirroring::Vertical);
+    ppu.set_scroll(400, 300); // Wraps around both edges
+    let mut image = RgbaImage::new(DEBUG_VIEW_WIDTH as u32, DEBUG_VIEW_HEIGHT as u32);
+    ppu.draw_debug_view(&mut image, 1);
+    check_golden("debug_view", &image);
+    ppu.draw_debug_view(&mut image, 6);
+    check_golden("debug_view_sprite_palette", &image);
+
+    // The outline follows the visible area
+    let outline = Rgba([255, 0, 255, 255]);
+    ppu.set_overscan(Overscan::Custom(DisplayRect { x: 16, y: 8, width: 200, height: 100 }));
+    ppu.draw_debug_view(&mut image, 1);
+    let (left, top) = (400 + 16, 300 + 8);
+    let (right, bottom) = ((left + 199) % 512, (top + 99) % 480);
+    for &(x, y) in &[(left, top), (right, top), (left, bottom), (right, bottom), (left + 50, top)] {
+        assert_eq!(*image.get_pixel(x as u32, y as u32), outline, "at {}, {}", x, y);
+    }
+    for &(x, y) in &[(400, 300), (left - 1, top), (left, top - 1), (right + 1, bottom), (right, bottom + 1)] {
+        assert!(*image.get_pixel(x as u32, y as u32) != outline, "at {}, {}", x, y);
+    }
+}
+
+#[test]
+fn pattern_tables() {
+    // The same scene, with the background's patterns moved to table 1 and the sprites' to table 0
+    let mut expected = make_test_ppu();
+    fill_background(&mut expected);
+    expected.set_sprite(0, 100, 100, RING_PATTERN_NAME, 1);
+    expected.set_sprite(1, 120, 100, ARROW_PATTERN_NAME, 2);
+
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    for name in 0..5 {
+        let pattern = ppu.get_pattern(name);
+        ppu.set_pattern(PATTERNS_PER_TABLE + name, pattern);
+        ppu.set_pattern(name, make_pattern(|_, _| 0));
+    }
+    ppu.set_pattern(100, ppu.get_pattern(PATTERNS_PER_TABLE + RING_PATTERN_NAME as usize));
+    ppu.set_pattern(101, ppu.get_pattern(PATTERNS_PER_TABLE + ARROW_PATTERN_NAME as usize));
+    ppu.set_sprite(0, 100, 100, 100, 1);
+    ppu.set_sprite(1, 120, 100, 101, 2);
+    ppu.set_bg_pattern_table(1);
+    ppu.set_sprite_pattern_table(0);
+    assert!(render(&ppu) == render(&expected));
+
+    // 8x16 sprites ignore the sprite pattern table
+    ppu.set_sprite_pattern_table(1);
+    ppu.set_sprite_size(SpriteSize::Size8x16);
+    ppu.set_sprite(0, 100, 100, 100, 1);
+    ppu.set_sprite(1, 0, 255, 0, 0);
+    let image = render(&ppu);
+    assert!(any_pixel_drawn(&ppu, &image, 100, 100, 8, 16));
+}
+
+#[test]
+fn ppuctrl() {
+    let mut ppu = Ppu::default();
+    ppu.set_scroll(300, 250);
+    ppu.write_ppuctrl(CTRL_NAMETABLE_Y | CTRL_INCREMENT_DOWN | CTRL_BG_TABLE | CTRL_SPRITE_8X16);
+    assert_eq!(ppu.scroll(), (44, 250));
+    assert_eq!(ppu.vram_increment, VramIncrement::Down);
+    assert_eq!((ppu.bg_pattern_table, ppu.sprite_pattern_table), (1, 0));
+    assert_eq!(ppu.sprite_size, SpriteSize::Size8x16);
+
+    ppu.write_ppuctrl(CTRL_NAMETABLE_X | CTRL_SPRITE_TABLE);
+    assert_eq!(ppu.scroll(), (300, 10));
+    assert_eq!(ppu.vram_increment, VramIncrement::Across);
+    assert_eq!((ppu.bg_pattern_table, ppu.sprite_pattern_table), (0, 1));
+    assert_eq!(ppu.sprite_size, SpriteSize::Size8x8);
+}
+
+// CHR data in which every byte of bank N is N, so each pattern shows which bank it came from
+fn numbered_chr_banks(num_banks: usize) -> Vec<u8> {
+    (0..num_banks * CHR_BANK_SIZE_IN_BYTES).map(|i| (i / CHR_BANK_SIZE_IN_BYTES) as u8).collect()
+}
+
+fn chr_bank_of_pattern(ppu: &Ppu, index: usize) -> u8 {
+    pattern_to_chr(&ppu.get_pattern(index))[0]
+}
+
+#[test]
+fn chr_banks() {
+    let mut ppu = Ppu::default();
+    assert_eq!(ppu.num_chr_banks(), NUM_CHR_WINDOWS);
+    assert!(ppu.load_chr_banks(&numbered_chr_banks(4)).is_err());
+    assert!(ppu.load_chr_banks(&[0; CHR_SIZE_IN_BYTES + 1]).is_err());
+
+    ppu.load_chr_banks(&numbered_chr_banks(32)).unwrap();
+    assert_eq!(ppu.num_chr_banks(), 32);
+    let banks = |ppu: &Ppu| -> Vec<u8> {
+        (0..NUM_CHR_WINDOWS).map(|window| chr_bank_of_pattern(ppu, window * PATTERNS_PER_CHR_BANK)).collect()
+    };
+    assert_eq!(banks(&ppu), [0, 1, 2, 3, 4, 5, 6, 7]);
+
+    ppu.set_chr_bank(ChrBankSize::Size1K, 7, 20);
+    ppu.set_chr_bank(ChrBankSize::Size2K, 1, 5);
+    ppu.set_chr_bank(ChrBankSize::Size4K, 0, 4);
+    assert_eq!(banks(&ppu), [16, 17, 18, 19, 4, 5, 6, 20]);
+    assert_eq!(chr_bank_of_pattern(&ppu, PATTERNS_PER_TABLE - 1), 19);
+
+    // Banks past the end wrap around
+    ppu.set_chr_bank(ChrBankSize::Size1K, 4, 33);
+    assert_eq!(chr_bank_of_pattern(&ppu, PATTERNS_PER_TABLE), 1);
+
+    // Patterns are written to the bank in their window
+    ppu.set_chr_bank(ChrBankSize::Size1K, 0, 9);
+    ppu.set_pattern(3, make_pattern(|_, _| 0));
+    ppu.set_chr_bank(ChrBankSize::Size1K, 0, 0);
+    assert_eq!(chr_bank_of_pattern(&ppu, 3), 0);
+    ppu.set_chr_bank(ChrBankSize::Size1K, 0, 9);
+    assert_eq!(chr_bank_of_pattern(&ppu, 3), 0);
+    assert_eq!(chr_bank_of_pattern(&ppu, 4), 9);
+
+    ppu.set_num_chr_banks(10);
+    assert_eq!(ppu.num_chr_banks(), 10);
+    assert_eq!(banks(&ppu), [0, 1, 2, 3, 4, 5, 6, 7]);
+}
+
+#[test]
+#[should_panic]
+fn chr_window_out_of_range() {
+    Ppu::default().set_chr_bank(ChrBankSize::Size2K, 4, 0);
+}
+
+#[test]
+fn chr_bank_raster_effect() {
+    // The background switches to the ring pattern's bank partway down. Sprites are banked too.
+    let mut ppu = make_test_ppu();
+    let ring = ppu.get_pattern(RING_PATTERN_NAME as usize);
+    ppu.set_num_chr_banks(NUM_CHR_WINDOWS + 1);
+    ppu.set_chr_bank(ChrBankSize::Size1K, 0, NUM_CHR_WINDOWS);
+    ppu.set_pattern(0, ring);
+    ppu.set_chr_bank(ChrBankSize::Size1K, 0, 0);
+    ppu.set_sprite(0, 100, 96, 0, 1);
+    ppu.add_raster_effect(100, RasterEffect::ChrBank(ChrBankSize::Size1K, 0, NUM_CHR_WINDOWS));
+    let image = render(&ppu);
+
+    let mut expected = make_test_ppu();
+    for y in 100 / TILE_HEIGHT_IN_PIXELS..TILEMAP_HEIGHT_IN_TILES {
+        for x in 0..TILEMAP_WIDTH_IN_TILES {
+            expected.set_tile(x, y, RING_PATTERN_NAME);
+        }
+    }
+    expected.set_sprite(0, 100, 96, RING_PATTERN_NAME, 1);
+    let expected_image = render(&expected);
+    for y in 0..DISPLAY_HEIGHT as u32 {
+        for x in 0..DISPLAY_WIDTH as u32 {
+            let expected_pixel = if y < 100 {
+                ppu.system_palette().get_color_rgba(ppu.read_palette(0), 0)
+            } else {
+                *expected_image.get_pixel(x, y)
+            };
+            assert_eq!(*image.get_pixel(x, y), expected_pixel, "at {}, {}", x, y);
+        }
+    }
+}
+
+// A busy frame: a scrolled background with a split, and sprites spread over most of the screen
+fn make_busy_ppu() -> Ppu {
+    let mut ppu = make_test_ppu();
+    fill_background(&mut ppu);
+    ppu.set_scroll(100, 30);
+    ppu.add_raster_effect(200, RasterEffect::ScrollX(0));
+    for n in 0..256 {
+        ppu.set_sprite(n, (n * 37 % 248) as i32, (n * 13 % 232) as i32, RING_PATTERN_NAME, (n % 4) as u8);
+    }
+    ppu
+}
+
+#[test]
+fn render_threads() {
+    // With a line over the sprite limit, sprite 0 over the background, and a palette split that
+    // every band after it must see
+    let mut ppu = make_busy_ppu();
+    ppu.add_raster_effect(210, RasterEffect::PaletteColor(0x00, 0x21));
+    ppu.set_sprite_limits(SpriteLimits::Hardware);
+    set_row_of_sprites(&mut ppu, 1, 10);
+    ppu.set_sprite(0, 128, 128, CHECKER_PATTERN_NAME, 0);
+    for &overscan in &[Overscan::Pal, Overscan::Ntsc, Overscan::Custom(DisplayRect { x: 3, y: 50, width: 200, height: 101 })] {
+        ppu.set_overscan(overscan);
+        ppu.set_render_threads(1);
+        let mut expected = render(&ppu);
+        let expected_status = ppu.draw_image(&mut expected);
+        assert!(expected_status.sprite_overflow && expected_status.sprite_zero_hit());
+        for &num_threads in &[2, 3, 7, DISPLAY_HEIGHT, 1000] {
+            ppu.set_render_threads(num_threads);
+            let mut image = render(&ppu);
+            assert_eq!(ppu.draw_image(&mut image), expected_status, "{} threads", num_threads);
+            assert!(image == expected, "{} threads", num_threads);
+        }
+    }
+}
+
+#[bench]
+fn bench_draw_image(b: &mut Bencher) {
+    let ppu = make_busy_ppu();
+    let mut image = render(&ppu);
+    b.iter(|| ppu.draw_image(&mut image));
+}
+
+#[bench]
+fn bench_draw_image_4_threads(b: &mut Bencher) {
+    let mut ppu = make_busy_ppu();
+    ppu.add_raster_effect(210, RasterEffect::PaletteColor(0x00, 0x21));
+    ppu.set_render_threads(4);
+    let mut image = render(&ppu);
+    b.iter(|| ppu.draw_image(&mut image));
+}
+
+// Draws in a pixel format into a buffer with 3 bytes of padding (0xAA) after each line
+fn draw_padded(ppu: &Ppu, format: PixelFormat) -> (Vec<u8>, usize) {
+    let area = ppu.visible_area();
+    let stride = area.width * format.bytes_per_pixel() + 3;
+    let mut buffer = vec![0xAA; stride * area.height];
+    ppu.draw_pixels(&mut buffer, stride, format);
+    (buffer, stride)
+}
+
+#[test]
+fn pixel_formats() {
+    let mut ppu = make_busy_ppu();
+    ppu.set_greyscale(true);
+    ppu.set_emphasis(MASK_EMPHASIZE_RED);
+    ppu.set_overscan(Overscan::Ntsc);
+    let expected = render(&ppu);
+    let area = ppu.visible_area();
+
+    for &num_threads in &[1, 3] {
+        ppu.set_render_threads(num_threads);
+        let (indexed, indexed_stride) = draw_padded(&ppu, PixelFormat::Indexed);
+        let (bgra, bgra_stride) = draw_padded(&ppu, PixelFormat::Bgra8888);
+        let (rgb565, rgb565_stride) = draw_padded(&ppu, PixelFormat::Rgb565);
+        for y in 0..area.height {
+            for x in 0..area.width {
+                let rgba = *expected.get_pixel(x as u32, y as u32);
+                let color = indexed[y * indexed_stride + x];
+                assert_eq!(color & !0x30, 0, "greyscale applies to indexed colors");
+                assert_eq!(ppu.system_palette().get_color_rgba(color, ppu.mask()), rgba);
+
+                let i = y * bgra_stride + x * 4;
+                assert_eq!(&bgra[i..i + 4], &[rgba[2], rgba[1], rgba[0], rgba[3]]);
+
+                let i = y * rgb565_stride + x * 2;
+                let pixel = rgb565[i] as u16 | (rgb565[i + 1] as u16) << 8;
+                assert_eq!(((pixel >> 11) as u8, (pixel >> 5) as u8 & 0x3f, pixel as u8 & 0x1f),
+                           (rgba[0] >> 3, rgba[1] >> 2, rgba[2] >> 3));
+            }
+            // Padding is left alone
+            assert_eq!(&indexed[y * indexed_stride + area.width..(y + 1) * indexed_stride], &[0xAA; 3]);
+        }
+    }
+}
+
+#[test]
+fn pixel_buffer_with_short_last_line() {
+    // The last line doesn't need the padding after it
+    let ppu = make_busy_ppu();
+    let stride = DISPLAY_WIDTH + 10;
+    let mut buffer = vec![0; stride * (DISPLAY_HEIGHT - 1) + DISPLAY_WIDTH];
+    ppu.draw_pixels(&mut buffer, stride, PixelFormat::Indexed);
+    let (padded, _) = draw_padded(&ppu, PixelFormat::Indexed);
+    assert_eq!(buffer[stride * (DISPLAY_HEIGHT - 1)..], padded[(DISPLAY_WIDTH + 3) * (DISPLAY_HEIGHT - 1)..][..DISPLAY_WIDTH]);
+}
+
+#[test]
+#[should_panic]
+fn pixel_buffer_too_small() {
+    let mut buffer = vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT - 1];
+    Ppu::default().draw_pixels(&mut buffer, DISPLAY_WIDTH, PixelFormat::Indexed);
+}
@@ -0,0 +1,364 @@
+// Loading patterns from PNG tilesheets, so tiles can be drawn in an image editor.
+// A tilesheet is a grid of 8x8 tiles, read left to right and then top to bottom into
+// consecutive pattern slots. An indexed PNG with at most four palette entries uses its
+// palette indices as pixel values, so the artist picks the numbering. Any other image may use
+// at most four colors in total, which become pixel values 0-3: fully transparent pixels are
+// always 0, and the other colors are numbered from darkest to lightest.
+
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use image;
+use image::{ImageFormat, Rgba, RgbaImage};
+use png;
+
+use nesppu;
+use nesppu::Ppu;
+
+const MAX_COLORS: usize = 4;
+
+#[derive(Debug)]
+pub enum TilesheetError {
+    Image(image::ImageError),
+    Png(png::DecodingError),
+    // The image isn't a whole number of tiles wide and high
+    BadSize { width: u32, height: u32 },
+    // A single tile uses more than four colors. x and y are in tiles.
+    TooManyColorsInTile { tile_x: u32, tile_y: u32, num_colors: usize },
+    // Every tile is fine on its own, but the sheet as a whole uses more than four colors
+    TooManyColors { num_colors: usize },
+    // A pixel of an indexed tilesheet uses a palette index past the four colors. x and y are in pixels.
+    PaletteIndexOutOfRange { x: u32, y: u32, index: u8 },
+    // The tiles don't fit in the pattern table after the first slot
+    TooManyTiles { first_pattern: usize, num_tiles: usize },
+}
+
+impl fmt::Display for TilesheetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TilesheetError::Image(ref e) => write!(f, "{}", e),
+            TilesheetError::Png(ref e) => write!(f, "{}", e),
+            TilesheetError::BadSize { width, height } =>
+                write!(f, "tilesheet is {}x{}, which is not a multiple of {}x{} tiles",
+                       width, height, nesppu::TILE_WIDTH_IN_PIXELS, nesppu::TILE_HEIGHT_IN_PIXELS),
+            TilesheetError::TooManyColorsInTile { tile_x, tile_y, num_colors } =>
+                write!(f, "tile at column {}, row {} uses {} colors; at most {} are allowed",
+                       tile_x, tile_y, num_colors, MAX_COLORS),
+            TilesheetError::TooManyColors { num_colors } =>
+                write!(f, "tilesheet uses {} colors; at most {} are allowed", num_colors, MAX_COLORS),
+            TilesheetError::PaletteIndexOutOfRange { x, y, index } =>
+                write!(f, "pixel at {}, {} uses palette index {}; only the first {} colors (0-{}) are allowed",
+                       x, y, index, MAX_COLORS, MAX_COLORS - 1),
+            TilesheetError::TooManyTiles { first_pattern, num_tiles } =>
+                write!(f, "{} tiles starting at pattern {} don't fit in the {} patterns",
+                       num_tiles, first_pattern, 2 * nesppu::PATTERNS_PER_TABLE),
+        }
+    }
+}
+
+impl error::Error for TilesheetError {}
+
+impl From<image::ImageError> for TilesheetError {
+    fn from(e: image::ImageError) -> Self {
+        TilesheetError::Image(e)
+    }
+}
+
+impl From<png::DecodingError> for TilesheetError {
+    fn from(e: png::DecodingError) -> Self {
+        TilesheetError::Png(e)
+    }
+}
+
+fn is_transparent(color: &Rgba<u8>) -> bool {
+    color[3] == 0
+}
+
+fn brightness(color: &Rgba<u8>) -> u32 {
+    // Rec. 601 luma, which is close enough to tell an artist's four shades apart
+    299 * color[0] as u32 + 587 * color[1] as u32 + 114 * color[2] as u32
+}
+
+// The distinct colors in a rectangle of the image. All fully transparent pixels count as one color.
+fn colors_in(image: &RgbaImage, x: u32, y: u32, width: u32, height: u32) -> Vec<Rgba<u8>> {
+    let mut colors = Vec::new();
+    for py in y..y + height {
+        for px in x..x + width {
+            let mut color = *image.get_pixel(px, py);
+            if is_transparent(&color) {
+                color = Rgba([0, 0, 0, 0]);
+            }
+            if !colors.contains(&color) {
+                colors.push(color);
+            }
+        }
+    }
+    colors
+}
+
+// The number of tiles across and down a tilesheet, if it fits in the pattern slots after first_pattern.
+fn tiles_in(dimensions: (u32, u32), first_pattern: usize) -> Result<(u32, u32), TilesheetError> {
+    let tile_width = nesppu::TILE_WIDTH_IN_PIXELS as u32;
+    let tile_height = nesppu::TILE_HEIGHT_IN_PIXELS as u32;
+    let (width, height) = dimensions;
+    if width % tile_width != 0 || height % tile_height != 0 {
+        return Err(TilesheetError::BadSize { width, height });
+    }
+
+    let (tiles_across, tiles_down) = (width / tile_width, height / tile_height);
+    let num_tiles = (tiles_across * tiles_down) as usize;
+    if first_pattern + num_tiles > 2 * nesppu::PATTERNS_PER_TABLE {
+        return Err(TilesheetError::TooManyTiles { first_pattern, num_tiles });
+    }
+    Ok((tiles_across, tiles_down))
+}
+
+// Store every tile of a sheet, taking the value of the pixel at (x, y) from pixel_at.
+fn store_tiles<F>(ppu: &mut Ppu, tiles_across: u32, tiles_down: u32, first_pattern: usize, pixel_at: F)
+    where F: Fn(u32, u32) -> u8
+{
+    let tile_width = nesppu::TILE_WIDTH_IN_PIXELS as u32;
+    let tile_height = nesppu::TILE_HEIGHT_IN_PIXELS as u32;
+    for tile_y in 0..tiles_down {
+        for tile_x in 0..tiles_across {
+            let mut pattern = [0u8; nesppu::PATTERN_SIZE_IN_BYTES];
+            for y in 0..tile_height {
+                for x in 0..tile_width {
+                    let pixel = pixel_at(tile_x * tile_width + x, tile_y * tile_height + y);
+                    nesppu::set_pixel_in_pattern(&mut pattern, x as usize, y as usize, pixel);
+                }
+            }
+            ppu.set_pattern(first_pattern + (tile_y * tiles_across + tile_x) as usize, pattern);
+        }
+    }
+}
+
+// Load the tiles of a tilesheet into the PPU's pattern slots, starting at first_pattern.
+// Colors are numbered by brightness. Returns the number of tiles loaded. Nothing is loaded if
+// there is an error.
+pub fn import_tilesheet(ppu: &mut Ppu, image: &RgbaImage, first_pattern: usize) -> Result<usize, TilesheetError> {
+    let tile_width = nesppu::TILE_WIDTH_IN_PIXELS as u32;
+    let tile_height = nesppu::TILE_HEIGHT_IN_PIXELS as u32;
+    let (width, height) = image.dimensions();
+    let (tiles_across, tiles_down) = tiles_in(image.dimensions(), first_pattern)?;
+
+    // Check each tile first, so that the error points at the tile to fix
+    for tile_y in 0..tiles_down {
+        for tile_x in 0..tiles_across {
+            let num_colors = colors_in(image, tile_x * tile_width, tile_y * tile_height,
+                                       tile_width, tile_height).len();
+            if num_colors > MAX_COLORS {
+                return Err(TilesheetError::TooManyColorsInTile {
+                    tile_x, tile_y, num_colors,
+                });
+            }
+        }
+    }
+
+    let mut colors = colors_in(image, 0, 0, width, height);
+    if colors.len() > MAX_COLORS {
+        return Err(TilesheetError::TooManyColors { num_colors: colors.len() });
+    }
+    colors.sort_by_key(|color| (!is_transparent(color), brightness(color)));
+
+    store_tiles(ppu, tiles_across, tiles_down, first_pattern, |x, y| {
+        let color = image.get_pixel(x, y);
+        if is_transparent(color) {
+            0
+        } else {
+            colors.iter().position(|c| c == color).unwrap() as u8
+        }
+    });
+
+    Ok((tiles_across * tiles_down) as usize)
+}
+
+// An image of palette indices, one byte per pixel.
+struct IndexedImage {
+    width: u32,
+    height: u32,
+    indices: Vec<u8>,
+}
+
+// Load the tiles of an indexed tilesheet, using each palette index as the pixel value.
+// Works like import_tilesheet otherwise.
+fn import_indexed_tilesheet(ppu: &mut Ppu, image: &IndexedImage, first_pattern: usize) -> Result<usize, TilesheetError> {
+    let (tiles_across, tiles_down) = tiles_in((image.width, image.height), first_pattern)?;
+    assert_eq!(image.indices.len(), (image.width * image.height) as usize);
+    if let Some(position) = image.indices.iter().position(|&index| index as usize >= MAX_COLORS) {
+        return Err(TilesheetError::PaletteIndexOutOfRange {
+            x: position as u32 % image.width, y: position as u32 / image.width, index: image.indices[position],
+        });
+    }
+
+    store_tiles(ppu, tiles_across, tiles_down, first_pattern, |x, y| {
+        image.indices[(y * image.width + x) as usize]
+    });
+
+    Ok((tiles_across * tiles_down) as usize)
+}
+
+// Read the palette indices of an indexed PNG with at most four palette entries.
+// Returns None for any other image, which has to be numbered by brightness instead.
+fn read_indexed_png(path: &Path) -> Result<Option<IndexedImage>, TilesheetError> {
+    if ImageFormat::from_path(path).ok() != Some(ImageFormat::Png) {
+        return Ok(None);
+    }
+    let file = File::open(path).map_err(image::ImageError::IoError)?;
+    let mut decoder = png::Decoder::new(file);
+    // Keep the indices instead of expanding them to colors
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let (info, mut reader) = decoder.read_info()?;
+    let num_entries = reader.info().palette.as_ref().map_or(0, |palette| palette.len() / 3);
+    if info.color_type != png::ColorType::Indexed || num_entries > MAX_COLORS {
+        return Ok(None);
+    }
+
+    let mut data = vec![0u8; info.buffer_size()];
+    reader.next_frame(&mut data)?;
+
+    // Rows are packed at 1, 2, 4 or 8 bits per pixel, leftmost pixel in the high bits
+    let bits = info.bit_depth as usize;
+    let mask = 0xffu8 >> (8 - bits);
+    let mut image = IndexedImage {
+        width: info.width,
+        height: info.height,
+        indices: Vec::with_capacity((info.width * info.height) as usize),
+    };
+    for row in data.chunks(info.line_size) {
+        for x in 0..info.width as usize {
+            let bit = x * bits;
+            image.indices.push(row[bit / 8] >> (8 - bits - bit % 8) & mask);
+        }
+    }
+    Ok(Some(image))
+}
+
+pub fn load_tilesheet<P: AsRef<Path>>(ppu: &mut Ppu, path: P, first_pattern: usize) -> Result<usize, TilesheetError> {
+    let path = path.as_ref();
+    if let Some(image) = read_indexed_png(path)? {
+        return import_indexed_tilesheet(ppu, &image, first_pattern);
+    }
+    let image = image::open(path)?.into_rgba8();
+    import_tilesheet(ppu, &image, first_pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+    const RED: Rgba<u8> = Rgba([200, 0, 0, 255]);
+    const GREY: Rgba<u8> = Rgba([128, 128, 128, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const CLEAR: Rgba<u8> = Rgba([10, 20, 30, 0]);
+
+    fn pixel_in_pattern(ppu: &Ppu, index: usize, x: usize, y: usize) -> u8 {
+        nesppu::get_pixel_from_pattern(&ppu.get_pattern(index), x, y)
+    }
+
+    #[test]
+    fn colors_numbered_by_brightness() {
+        // Two tiles side by side, in four shades
+        let image = RgbaImage::from_fn(16, 8, |x, _| [WHITE, GREY, RED, BLACK][x as usize / 4]);
+        let mut ppu = Ppu::default();
+        assert_eq!(import_tilesheet(&mut ppu, &image, 10).unwrap(), 2);
+        assert_eq!(pixel_in_pattern(&ppu, 10, 0, 0), 3);
+        assert_eq!(pixel_in_pattern(&ppu, 10, 4, 0), 2);
+        assert_eq!(pixel_in_pattern(&ppu, 11, 0, 0), 1);
+        assert_eq!(pixel_in_pattern(&ppu, 11, 4, 0), 0);
+    }
+
+    #[test]
+    fn transparent_is_color_zero() {
+        let image = RgbaImage::from_fn(8, 16, |x, y| if y >= 8 { BLACK } else if x < 4 { CLEAR } else { WHITE });
+        let mut ppu = Ppu::default();
+        import_tilesheet(&mut ppu, &image, 0).unwrap();
+        assert_eq!(pixel_in_pattern(&ppu, 0, 0, 0), 0);
+        assert_eq!(pixel_in_pattern(&ppu, 0, 7, 0), 2);
+        assert_eq!(pixel_in_pattern(&ppu, 1, 0, 0), 1);
+    }
+
+    // Write an indexed PNG with the given palette to the temp directory, one byte per index in data.
+    fn write_indexed_png(name: &str, width: u32, height: u32, bit_depth: png::BitDepth,
+                         palette: &[Rgba<u8>], data: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("lightning-dodge-{}-{}.png", name, process::id()));
+        let mut encoder = png::Encoder::new(File::create(&path).unwrap(), width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(bit_depth);
+        encoder.set_palette(palette.iter().flat_map(|color| color.0[..3].to_vec()).collect());
+        encoder.write_header().unwrap().write_image_data(data).unwrap();
+        path
+    }
+
+    #[test]
+    fn indexed_png_keeps_palette_indices() {
+        // Two tiles side by side, with palette indices 0-3 out of brightness order.
+        // Four pixels per byte at two bits each, so each byte is a single index.
+        let data: Vec<u8> = (0..8 * 4).map(|i| [0x00, 0x55, 0xaa, 0xff][i % 4]).collect();
+        let path = write_indexed_png("indexed", 16, 8, png::BitDepth::Two, &[WHITE, BLACK, RED, GREY], &data);
+        let mut ppu = Ppu::default();
+        let result = load_tilesheet(&mut ppu, &path, 10);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(pixel_in_pattern(&ppu, 10, 0, 0), 0);
+        assert_eq!(pixel_in_pattern(&ppu, 10, 7, 7), 1);
+        assert_eq!(pixel_in_pattern(&ppu, 11, 0, 0), 2);
+        assert_eq!(pixel_in_pattern(&ppu, 11, 7, 7), 3);
+
+        // With more than four palette entries, the colors are numbered by brightness instead
+        let data: Vec<u8> = (0..16 * 8).map(|i| [0, 1, 2, 3][i % 16 / 4]).collect();
+        let path = write_indexed_png("fallback", 16, 8, png::BitDepth::Eight,
+                                     &[WHITE, BLACK, RED, GREY, WHITE], &data);
+        let result = load_tilesheet(&mut ppu, &path, 10);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(pixel_in_pattern(&ppu, 10, 0, 0), 3);
+        assert_eq!(pixel_in_pattern(&ppu, 10, 4, 0), 0);
+        assert_eq!(pixel_in_pattern(&ppu, 11, 0, 0), 1);
+        assert_eq!(pixel_in_pattern(&ppu, 11, 4, 0), 2);
+    }
+
+    #[test]
+    fn errors() {
+        let mut ppu = Ppu::default();
+        match import_tilesheet(&mut ppu, &RgbaImage::new(12, 8), 0) {
+            Err(TilesheetError::BadSize { width: 12, height: 8 }) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+
+        // Second tile in the second row has five colors
+        let image = RgbaImage::from_fn(16, 16, |x, y| {
+            if x >= 8 && y >= 8 { [WHITE, GREY, RED, BLACK, CLEAR][x as usize % 5] } else { BLACK }
+        });
+        match import_tilesheet(&mut ppu, &image, 0) {
+            Err(TilesheetError::TooManyColorsInTile { tile_x: 1, tile_y: 1, num_colors: 5 }) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+
+        let colors = [WHITE, GREY, RED, BLACK, Rgba([0, 0, 200, 255])];
+        let image = RgbaImage::from_fn(40, 8, |x, _| colors[x as usize / 8]);
+        match import_tilesheet(&mut ppu, &image, 0) {
+            Err(TilesheetError::TooManyColors { num_colors: 5 }) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+
+        let mut image = IndexedImage { width: 16, height: 8, indices: vec![0; 16 * 8] };
+        image.indices[3 * 16 + 9] = 4;
+        match import_indexed_tilesheet(&mut ppu, &image, 0) {
+            Err(TilesheetError::PaletteIndexOutOfRange { x: 9, y: 3, index: 4 }) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+
+        match import_tilesheet(&mut ppu, &RgbaImage::new(16, 8), 511) {
+            Err(TilesheetError::TooManyTiles { first_pattern: 511, num_tiles: 2 }) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}